@@ -0,0 +1,120 @@
+use crate::android_project::get_target_android_name;
+use crate::message::*;
+use crate::toolchain::*;
+use crate::ANDROID_TARGETS;
+use std::env;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// The adb set with CARGO_SDL_APK_ADB, used instead of the SDK's.
+pub fn get_adb_override() -> Option<PathBuf> {
+    env::var_os("CARGO_SDL_APK_ADB")
+        .filter(|adb| !adb.is_empty())
+        .map(PathBuf::from)
+}
+
+pub fn get_adb(manifest_path: &Path) -> PathBuf {
+    get_adb_override()
+        .unwrap_or_else(|| get_android_sdk_dir(manifest_path).join("platform-tools/adb"))
+}
+
+/// Selects the device all adb commands talk to, the one connected over USB.
+pub const ADB_DEVICE: &str = "-d";
+
+/// An adb command for the connected device.
+pub fn adb_command(adb: &Path) -> Command {
+    let mut command = Command::new(adb);
+    command.arg(ADB_DEVICE);
+    command
+}
+
+/// ABIs supported by the connected device, preferred first.
+pub fn get_device_abis(manifest_path: &Path) -> Vec<String> {
+    let output = adb_command(&get_adb(manifest_path))
+        .args(["shell", "getprop", "ro.product.cpu.abilist"])
+        .output()
+        .expect("Unable to run adb");
+    let abis = String::from_utf8_lossy(&output.stdout).trim().to_string();
+    if !abis.is_empty() {
+        return abis.split(',').map(|abi| abi.trim().to_string()).collect();
+    }
+
+    // Devices before Android 5 only have a single ABI
+    let output = adb_command(&get_adb(manifest_path))
+        .args(["shell", "getprop", "ro.product.cpu.abi"])
+        .output()
+        .expect("Unable to run adb");
+    vec![String::from_utf8_lossy(&output.stdout).trim().to_string()]
+}
+
+/// The build target matching the connected device, preferred ABI first.
+pub fn get_device_target(manifest_path: &Path) -> &'static str {
+    let device_abis = get_device_abis(manifest_path);
+    device_abis
+        .iter()
+        .find_map(|abi| {
+            ANDROID_TARGETS
+                .into_iter()
+                .find(|t| get_target_android_name(t) == abi)
+        })
+        .unwrap_or_else(|| {
+            error(&format!(
+                "No build target for the device's ABIs ({}).",
+                device_abis.join(", ")
+            ));
+            fail();
+        })
+}
+
+/// Picks the APK to install on the connected device. Split APKs are matched
+/// against the device's ABIs, falling back to an APK with all ABIs.
+pub fn select_device_apk(manifest_path: &Path, apks: &[(Option<String>, PathBuf)]) -> PathBuf {
+    if let [(None, apk)] = apks {
+        return apk.clone();
+    }
+
+    let device_abis = get_device_abis(manifest_path);
+    for device_abi in &device_abis {
+        if let Some((_, apk)) = apks
+            .iter()
+            .find(|(abi, _)| abi.as_ref() == Some(device_abi))
+        {
+            return apk.clone();
+        }
+    }
+
+    match apks
+        .iter()
+        .find(|(abi, _)| abi.is_none() || abi.as_deref() == Some("universal"))
+    {
+        Some((_, apk)) => apk.clone(),
+        None => {
+            error(&format!(
+                "No APK for the device's ABIs ({}). Build with --universal-apk to get one with all ABIs.",
+                device_abis.join(", ")
+            ));
+            fail();
+        }
+    }
+}
+
+/// Quotes an argument for `adb shell`, which runs it through the device's
+/// shell.
+pub fn shell_quote(arg: &str) -> String {
+    format!("'{}'", arg.replace('\'', r"'\''"))
+}
+
+/// Arguments for `am start` passing `values` as a string array extra.
+pub fn string_array_extra(key: &str, values: &[String]) -> Vec<String> {
+    if values.is_empty() {
+        return vec![];
+    }
+
+    // am splits the values at commas that aren't escaped
+    let values: Vec<String> = values.iter().map(|v| v.replace(',', r"\,")).collect();
+    vec![
+        "--esa".to_string(),
+        key.to_string(),
+        shell_quote(&values.join(",")),
+    ]
+}
@@ -1,47 +1,208 @@
+use crate::assets::*;
+use crate::build_bin_as_lib::{BuildTarget, CrateSdl};
+use crate::icon::*;
+use crate::message::*;
+use crate::sdl_source::*;
+use crate::toolchain::*;
 use crate::util::*;
-use crate::BuildProfile;
+use crate::{BuildOptions, BuildProfile};
 use fs_extra::{copy_items, dir::CopyOptions};
-use image::imageops::{resize, FilterType};
 use regex::Regex;
 use std::collections::HashMap;
-use std::fs::{copy, create_dir_all, read_to_string, write};
-use std::path::Path;
+use std::fs::{copy, create_dir_all, read_dir, read_to_string, write};
+use std::path::{Path, PathBuf};
 use std::process::Command;
-use symlink::symlink_dir;
+use std::sync::{Arc, Condvar, Mutex};
+use symlink::remove_symlink_dir;
+
+/// Tracks the SDL builds running in the background, so the Rust build can
+/// wait for the libSDL2.so it links against.
+pub struct SdlBuild {
+    finished: Mutex<HashMap<String, bool>>,
+    changed: Condvar,
+}
+
+impl SdlBuild {
+    /// For SDL that an earlier build already built for the targets.
+    pub fn built(targets: &[&str]) -> Arc<Self> {
+        Arc::new(SdlBuild {
+            finished: Mutex::new(
+                targets
+                    .iter()
+                    .map(|target| (target.to_string(), true))
+                    .collect(),
+            ),
+            changed: Condvar::new(),
+        })
+    }
+
+    fn finish(&self, target: &str, success: bool) {
+        self.finished
+            .lock()
+            .unwrap()
+            .insert(target.to_string(), success);
+        self.changed.notify_all();
+    }
+
+    /// Blocks until SDL is built for the target. Returns an error message
+    /// if that failed.
+    pub fn wait(&self, target: &str) -> Result<(), String> {
+        let mut finished = self.finished.lock().unwrap();
+        loop {
+            match finished.get(target) {
+                Some(true) => return Ok(()),
+                Some(false) => return Err(format!("Building SDL for {target} failed.")),
+                None => finished = self.changed.wait(finished).unwrap(),
+            }
+        }
+    }
+
+    pub fn wait_all(&self, targets: &[&str]) -> Result<(), String> {
+        targets.iter().try_for_each(|target| self.wait(target))
+    }
+}
+
+/// Intent extras with the arguments and environment for SDL_main.
+pub const INTENT_ARGS: &str = "sdl_args";
+pub const INTENT_ENV: &str = "sdl_env";
+
+/// Appended to the package name of the test APK, so it's installed next to
+/// the app.
+pub const TEST_APP_ID_SUFFIX: &str = ".test";
+
+/// Where SDL comes from: built by ndk-build from the SDL source, or by the
+/// sdl2-sys crate as part of the Rust build.
+#[derive(Clone)]
+pub enum SdlSource {
+    NdkBuild(Arc<SdlBuild>),
+    Crate(CrateSdl),
+}
+
+impl SdlSource {
+    /// Waits until ndk-build finished building SDL for the targets. Fails if
+    /// that failed.
+    pub fn wait(&self, targets: &[&str]) {
+        if let SdlSource::NdkBuild(sdl_build) = self {
+            if let Err(message) = sdl_build.wait_all(targets) {
+                error(&message);
+                fail();
+            }
+        }
+    }
+
+    fn android_project_dir(&self, manifest_path: &Path) -> PathBuf {
+        match self {
+            SdlSource::Crate(CrateSdl {
+                android_project: Some(dir),
+                ..
+            }) => dir.clone(),
+            _ => get_sdl_dir(manifest_path).join("android-project"),
+        }
+    }
+
+    /// Libraries the activity loads. Statically linked SDL is in libmain.
+    fn libraries(&self) -> &'static [&'static str] {
+        match self {
+            SdlSource::Crate(crate_sdl) if crate_sdl.static_link => &["main"],
+            _ => &["SDL2", "main"],
+        }
+    }
+}
+
+fn get_sdl_build_dir(manifest_dir: &Path, rust_name: &str) -> PathBuf {
+    manifest_dir
+        .join("target/android-sdl")
+        .join(get_target_android_name(rust_name))
+}
+
+/// Where the SDL libraries built for the target end up.
+pub fn get_sdl_libs_dir(manifest_dir: &Path, rust_name: &str) -> PathBuf {
+    get_sdl_build_dir(manifest_dir, rust_name)
+        .join("libs")
+        .join(get_target_android_name(rust_name))
+}
 
-pub fn build_sdl_for_android(manifest_path: &Path, targets: &Vec<&str>, profile: BuildProfile) {
+fn build_sdl_for_target(manifest_path: &Path, rust_name: &str, profile: BuildProfile, jobs: u32) -> bool {
     let manifest_dir = manifest_path.parent().unwrap();
-    let p = Path::new(&*get_env_var("ANDROID_NDK_HOME")).join("ndk-build");
-
-    assert!(Command::new(p)
-        .args([
-            "NDK_PROJECT_PATH=.",
-            "APP_BUILD_SCRIPT=./Android.mk",
-            "APP_PLATFORM=android-18"
-        ])
-        .current_dir(&*get_env_var("SDL"))
+    let android_name = get_target_android_name(rust_name);
+    let p = get_android_ndk_dir(manifest_path).join("ndk-build");
+
+    // Everything is built into the target dir, so $SDL is only read and can
+    // be shared by projects with different settings. Each ABI gets its own
+    // output dirs, so the builds don't interfere.
+    let out_dir = get_sdl_build_dir(manifest_dir, rust_name);
+    create_dir_all(&out_dir).expect("Unable to create SDL build dir");
+    let stage = Stage::start(&format!("sdl-{android_name}"));
+    let success = Command::new(p)
+        .args(["NDK_PROJECT_PATH=null", "APP_PLATFORM=android-18"])
+        .arg(format!(
+            "APP_BUILD_SCRIPT={}",
+            get_sdl_dir(manifest_path).join("Android.mk").display()
+        ))
+        .arg(format!("APP_ABI={android_name}"))
+        .arg(format!("NDK_OUT={}", out_dir.join("obj").display()))
+        .arg(format!("NDK_LIBS_OUT={}", out_dir.join("libs").display()))
+        .arg(format!("-j{jobs}"))
+        .current_dir(&out_dir)
+        .stdout(subprocess_stdout())
         .status()
         .unwrap()
-        .success());
+        .success();
+    if !success {
+        return false;
+    }
 
-    for rust_name in targets {
-        let android_name = get_target_android_name(rust_name);
-        let rust_dir = manifest_dir
-            .join("target")
-            .join(rust_name)
-            .join(profile.to_string())
-            .join("deps");
-
-        create_dir_all(&rust_dir).expect("Unable to create target dir");
-        copy(
-            Path::new(&*get_env_var("SDL"))
-                .join("libs")
-                .join(android_name)
-                .join("libSDL2.so"),
-            rust_dir.join("libSDL2.so"),
-        )
-        .expect("Unable to copy SDL dependencies");
+    let rust_dir = manifest_dir
+        .join("target")
+        .join(rust_name)
+        .join(profile.to_string())
+        .join("deps");
+
+    create_dir_all(&rust_dir).expect("Unable to create target dir");
+    copy(
+        get_sdl_libs_dir(manifest_dir, rust_name).join("libSDL2.so"),
+        rust_dir.join("libSDL2.so"),
+    )
+    .expect("Unable to copy SDL dependencies");
+    stage.finish();
+
+    true
+}
+
+/// Builds SDL for the targets in the background. At most `jobs` jobs are
+/// run, so the targets are built in parallel only when there are enough.
+pub fn build_sdl_for_android(
+    manifest_path: &Path,
+    targets: &[&str],
+    profile: BuildProfile,
+    jobs: u32,
+) -> Arc<SdlBuild> {
+    let sdl_build = Arc::new(SdlBuild {
+        finished: Mutex::new(HashMap::new()),
+        changed: Condvar::new(),
+    });
+    let builders = (targets.len() as u32).min(jobs).max(1);
+    let jobs = (jobs / builders).max(1);
+    let queue = Arc::new(Mutex::new(
+        targets.iter().map(|t| t.to_string()).collect::<Vec<_>>(),
+    ));
+
+    for _ in 0..builders {
+        let manifest_path = manifest_path.to_path_buf();
+        let queue = queue.clone();
+        let sdl_build = sdl_build.clone();
+        std::thread::spawn(move || loop {
+            let Some(target) = queue.lock().unwrap().pop() else {
+                break;
+            };
+            let success = std::panic::catch_unwind(|| {
+                build_sdl_for_target(&manifest_path, &target, profile, jobs)
+            });
+            sdl_build.finish(&target, matches!(success, Ok(true)));
+        });
     }
+
+    sdl_build
 }
 
 pub fn get_target_android_name(rust_target_name: &str) -> &str {
@@ -63,6 +224,122 @@ pub fn get_android_app_id(manifest_path: &Path) -> String {
     .unwrap_or("org.libsdl.app".to_string())
 }
 
+/// Fully qualified name of the custom activity class, if one is configured.
+/// Names starting with a dot are relative to the app's package name.
+pub fn get_android_activity(manifest_path: &Path) -> Option<String> {
+    get_toml_string(
+        manifest_path,
+        vec!["package", "metadata", "android", "activity"],
+    )
+    .map(|activity| {
+        if activity.starts_with('.') {
+            get_android_app_id(manifest_path) + &activity
+        } else {
+            activity
+        }
+    })
+}
+
+/// The generated activity also takes the arguments and environment passed by
+/// `cargo sdl-apk run` as intent extras.
+fn main_activity_source(appid: &str, libraries: &[&str]) -> String {
+    let libraries: Vec<String> = libraries.iter().map(|l| format!("\"{l}\"")).collect();
+
+    format!(
+        r#"package {appid};
+
+import android.os.Bundle;
+import android.system.ErrnoException;
+import android.system.Os;
+import org.libsdl.app.SDLActivity;
+
+public class MainActivity extends SDLActivity {{
+    @Override
+    protected void onCreate(Bundle savedInstanceState) {{
+        // Set before SDL_main is started by SDLActivity
+        String[] env = getIntent().getStringArrayExtra("{INTENT_ENV}");
+        if (env != null) {{
+            for (String variable : env) {{
+                String[] parts = variable.split("=", 2);
+                try {{
+                    Os.setenv(parts[0], parts.length > 1 ? parts[1] : "", true);
+                }} catch (ErrnoException e) {{
+                    throw new RuntimeException(e);
+                }}
+            }}
+        }}
+        super.onCreate(savedInstanceState);
+    }}
+
+    @Override
+    protected String[] getLibraries() {{
+        return new String[] {{ {} }};
+    }}
+
+    @Override
+    protected String[] getArguments() {{
+        String[] args = getIntent().getStringArrayExtra("{INTENT_ARGS}");
+        return args != null ? args : new String[0];
+    }}
+}}
+"#,
+        libraries.join(", ")
+    )
+}
+
+/// Reads the fully qualified name of the launcher activity from the
+/// generated AndroidManifest.xml.
+pub fn get_launcher_activity(manifest_path: &Path) -> String {
+    let manifest_dir = manifest_path.parent().unwrap();
+    let path = manifest_dir.join("target/android-project/app/src/main/AndroidManifest.xml");
+    let xml = read_to_string(&path).expect("Unable to read AndroidManifest.xml");
+
+    let package = Regex::new(r#"<manifest[^>]*\spackage="([^"]+)""#)
+        .unwrap()
+        .captures(&xml)
+        .map(|c| c[1].to_string());
+    let activities = Regex::new(r"(?s)<activity\b([^>]*)>(.*?)</activity>").unwrap();
+    let name = Regex::new(r#"android:name="([^"]+)""#).unwrap();
+
+    for activity in activities.captures_iter(&xml) {
+        if !activity[2].contains("android.intent.category.LAUNCHER") {
+            continue;
+        }
+
+        let class = name
+            .captures(&activity[1])
+            .expect("Launcher activity without android:name")[1]
+            .to_string();
+        return match package {
+            Some(package) if class.starts_with('.') => package + &class,
+            Some(package) if !class.contains('.') => format!("{package}.{class}"),
+            _ => class,
+        };
+    }
+
+    panic!("No launcher activity in {}", path.display());
+}
+
+fn overlay_android_project_dir(manifest_path: &Path, key: &str, dest: &str) {
+    let manifest_dir = manifest_path.parent().unwrap();
+
+    if let Some(dir) = get_toml_string(manifest_path, vec!["package", "metadata", "android", key]) {
+        let dir = manifest_dir.join(dir);
+        if !dir.is_dir() {
+            panic!("Directory {} for {} does not exist", dir.display(), key);
+        }
+
+        let dest = manifest_dir.join("target/android-project").join(dest);
+        create_dir_all(&dest).unwrap();
+        fs_extra::dir::copy(
+            dir,
+            dest,
+            &CopyOptions::new().overwrite(true).content_only(true),
+        )
+        .unwrap();
+    }
+}
+
 fn use_permission(manifest_dir: &Path, permission: &str) {
     change_android_project_file(
         manifest_dir,
@@ -71,7 +348,99 @@ fn use_permission(manifest_dir: &Path, permission: &str) {
     );
 }
 
-fn create_android_project(manifest_path: &Path, target_artifacts: &HashMap<String, String>) {
+/// Gradle config building one APK per ABI. The version code of each split
+/// is prefixed with a code for its ABI, so stores serve the right one.
+fn abi_splits_gradle(abis: &[&str], universal_apk: bool) -> String {
+    let include: Vec<String> = abis.iter().map(|abi| format!("'{abi}'")).collect();
+
+    format!(
+        r#"
+android {{
+    splits {{
+        abi {{
+            enable true
+            reset()
+            include {}
+            universalApk {universal_apk}
+        }}
+    }}
+}}
+
+ext.abiCodes = ['armeabi-v7a': 1, 'arm64-v8a': 2, 'x86': 3, 'x86_64': 4]
+
+android.applicationVariants.all {{ variant ->
+    variant.outputs.each {{ output ->
+        def abi = output.getFilter(com.android.build.OutputFile.ABI)
+        if (abi != null) {{
+            output.versionCodeOverride = project.ext.abiCodes.get(abi) * 1000 + variant.versionCode
+        }}
+    }}
+}}
+"#,
+        include.join(", ")
+    )
+}
+
+/// Removes all `name { ... }` blocks from a gradle file. Blocks in comments
+/// are kept, braces in comments are ignored.
+fn remove_gradle_blocks(gradle: &str, name: &str) -> String {
+    let start = Regex::new(&format!(r"(?m)^[ \t]*{}\s*\{{", regex::escape(name))).unwrap();
+
+    let mut result = String::new();
+    let mut rest = gradle;
+    while let Some(m) = start.find(rest) {
+        result.push_str(&rest[..m.start()]);
+
+        let mut depth = 1;
+        let mut end = rest.len();
+        let mut in_comment = false;
+        let mut chars = rest[m.end()..].char_indices().peekable();
+        while let Some((i, c)) = chars.next() {
+            match c {
+                '\n' => in_comment = false,
+                '/' if !in_comment && matches!(chars.peek(), Some((_, '/'))) => in_comment = true,
+                '{' if !in_comment => depth += 1,
+                '}' if !in_comment => {
+                    depth -= 1;
+                    if depth == 0 {
+                        end = m.end() + i + 1;
+                        break;
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        rest = &rest[end..];
+        rest = rest.strip_prefix('\n').unwrap_or(rest);
+    }
+    result.push_str(rest);
+
+    result
+}
+
+/// Copies libmain.so of each target into the android project.
+pub fn copy_android_libs(manifest_path: &Path, target_artifacts: &HashMap<String, String>) {
+    let manifest_dir = manifest_path.parent().unwrap();
+
+    for (target, artifact) in target_artifacts {
+        let target_android_name = get_target_android_name(target);
+
+        let android_dir = manifest_dir
+            .join("target/android-project/app/src/main/jniLibs")
+            .join(target_android_name);
+
+        create_dir_all(&android_dir).unwrap();
+        copy(artifact, android_dir.join("libmain.so")).unwrap();
+    }
+}
+
+fn create_android_project(
+    manifest_path: &Path,
+    target_artifacts: &HashMap<String, String>,
+    options: &BuildOptions,
+    sdl: &SdlSource,
+) {
     let manifest_dir = manifest_path.parent().unwrap();
     let appid = get_android_app_id(manifest_path);
 
@@ -80,10 +449,24 @@ fn create_android_project(manifest_path: &Path, target_artifacts: &HashMap<Strin
         vec!["package", "metadata", "android", "title"],
     )
     .unwrap_or("Untitled".to_string());
+    let appname = if options.test_app {
+        appname + " Tests"
+    } else {
+        appname
+    };
+
+    // Sources and resources are restored from the template, so ones
+    // removed from java_sources or res don't stay in the project.
+    for dir in ["app/src/main/java", "app/src/main/res"] {
+        let dir = manifest_dir.join("target/android-project").join(dir);
+        if dir.exists() {
+            std::fs::remove_dir_all(&dir).unwrap();
+        }
+    }
 
     // Copy template project from SDL
     copy_items(
-        &[Path::new(&*get_env_var("SDL")).join("android-project")],
+        &[sdl.android_project_dir(manifest_path)],
         Path::new(manifest_dir).join("target"),
         &CopyOptions::new().skip_exist(false).overwrite(true),
     )
@@ -93,13 +476,35 @@ fn create_android_project(manifest_path: &Path, target_artifacts: &HashMap<Strin
     let java_main_folder = manifest_dir
         .join("target/android-project/app/src/main/java")
         .join(str::replace(&appid, ".", "/"));
-    create_dir_all(java_main_folder).unwrap();
+    create_dir_all(&java_main_folder).unwrap();
+    if get_android_activity(manifest_path).is_none() {
+        write(
+            java_main_folder.join("MainActivity.java"),
+            main_activity_source(&appid, sdl.libraries()),
+        )
+        .unwrap();
+    }
 
+    // The SDK and NDK may have been discovered without the env vars gradle
+    // looks at, so point it to them explicitly.
+    let ndk_path = format!(
+        "android {{\n    ndkPath \"{}\"",
+        get_android_ndk_dir(manifest_path).display()
+    );
     change_android_project_file(
         manifest_dir,
         "app/build.gradle",
-        vec![("org.libsdl.app", &*appid), ("minSdkVersion 16", "minSdkVersion 26")],
+        vec![
+            ("org.libsdl.app", &*appid),
+            ("minSdkVersion 16", "minSdkVersion 26"),
+            ("android {", &*ndk_path),
+        ],
     );
+    write(
+        manifest_dir.join("target/android-project/local.properties"),
+        format!("sdk.dir={}\n", get_android_sdk_dir(manifest_path).display()),
+    )
+    .unwrap();
 
     change_android_project_file(
         manifest_dir,
@@ -121,150 +526,54 @@ fn create_android_project(manifest_path: &Path, target_artifacts: &HashMap<Strin
         }
     }
 
-    // Link SDL into project
-    if !manifest_dir
-        .join("target/android-project/app/jni/SDL")
-        .is_dir()
-    {
-        symlink_dir(
-            Path::new(&*get_env_var("SDL")),
-            manifest_dir.join("target/android-project/app/jni/SDL"),
-        )
-        .unwrap();
+    // SDL was already built by build_sdl_for_android, so gradle packages
+    // that instead of building it again.
+    let stale_sdl_link = manifest_dir.join("target/android-project/app/jni/SDL");
+    if stale_sdl_link.is_symlink() {
+        remove_symlink_dir(stale_sdl_link).unwrap();
     }
-
     {
         let path = manifest_dir.join("target/android-project/app/build.gradle");
-        let first = Regex::new(
-            r"(externalNativeBuild\s\{)([^\}]*)(abiFilters)([^\}]*)(\})([^\}]*)(\})([^\}]*)(\})",
-        )
-        .unwrap();
-        let second = Regex::new(
-            r"(externalNativeBuild\s\{)([^\}]*)(path)([^\}]*)(\})([^\}]*)(\})([^\}]*)(\})",
-        )
-        .unwrap();
-        let string = std::fs::read_to_string(&path).unwrap();
-        let mut new = first.replace(
-            &string,
-            r#"externalNativeBuild {
-            cmake {
-                arguments "-DANDROID_APP_PLATFORM=android-19", "-DANDROID_STL=c++_static"
-                //abiFilters 'armeabi-v7a', 'arm64-v8a', 'x86', 'x86_64'
-                abiFilters 'armeabi-v7a', 'arm64-v8a', 'x86'
-            }
-        }"#,
-        );
-        let new_string = new.to_string();
-        new = second.replace(
-            &new_string,
-            r#"externalNativeBuild {
-            cmake {
-                path 'jni/CMakeLists.txt'
-            }
-        }"#,
-        );
-        std::fs::write(path, new.to_string()).unwrap();
-
-        change_android_project_file(
-            manifest_dir,
-            "app/jni/CMakeLists.txt",
-            vec![("add_subdirectory(src)", "")],
-        )
+        let mut gradle = read_to_string(&path).unwrap();
+        gradle = remove_gradle_blocks(&gradle, "externalNativeBuild");
+        gradle = remove_gradle_blocks(&gradle, "applicationVariants.all");
+        write(path, gradle).unwrap();
     }
 
-    // Copy libmain.so to all targets
-    for (target, artifact) in target_artifacts {
-        let target_android_name = get_target_android_name(target);
-
-        let android_dir = manifest_dir
-            .join("target/android-project/app/src/main/jniLibs")
-            .join(target_android_name);
-
-        create_dir_all(&android_dir).unwrap();
-        copy(artifact, android_dir.join("libmain.so")).unwrap();
+    // Libraries of ABIs left out of this build mustn't be packaged
+    let jni_libs_dir = manifest_dir.join("target/android-project/app/src/main/jniLibs");
+    if jni_libs_dir.exists() {
+        std::fs::remove_dir_all(&jni_libs_dir).unwrap();
     }
 
+    for target in target_artifacts.keys() {
+        let android_dir = jni_libs_dir.join(get_target_android_name(target));
+        create_dir_all(&android_dir).unwrap();
 
-    {
-        let icon = get_toml_string(
-            manifest_path,
-            vec!["package", "metadata", "android", "icon"],
-        );
-        let adaptive_icon_foreground = get_toml_string(
-            manifest_path,
-            vec!["package", "metadata", "android", "adaptive_icon_foreground"],
-        );
-        let adaptive_icon_background = get_toml_string(
-            manifest_path,
-            vec!["package", "metadata", "android", "adaptive_icon_background"],
-        );
-        let adaptive_icon_monochrome = get_toml_string(
-            manifest_path,
-            vec!["package", "metadata", "android", "adaptive_icon_monochrome"],
-        );
-        if (adaptive_icon_foreground.is_some() || adaptive_icon_background.is_some() || adaptive_icon_monochrome.is_some()) && icon.is_some() {
-            panic!("You can only specify an icon OR an adaptive icon");
-        }
-        {
-            let res_dir = manifest_dir.join("target/android-project/app/src/main/res");
-            let versions = [
-                ("mdpi", 48),
-                ("hdpi", 72),
-                ("xhdpi", 96),
-                ("xxhdpi", 144),
-                ("xxxhdpi", 192),
-            ];
-            if let Some(icon) = icon {
-                let image = image::open(manifest_dir.join(icon)).unwrap();
-
-                for (res, size) in versions {
-                    let dir = res_dir.join(format!("mipmap-{res}"));
-                    std::fs::remove_dir_all(&dir).unwrap();
-                    std::fs::create_dir_all(&dir).unwrap();
-                    let img = resize(&image, size, size, FilterType::Gaussian);
-                    img.save(dir.join("ic_launcher.png")).unwrap();
-                }
+        // There are none if sdl2-sys linked SDL statically
+        let Ok(libs) = read_dir(get_sdl_libs_dir(manifest_dir, target)) else {
+            continue;
+        };
+        for lib in libs {
+            let lib = lib.unwrap().path();
+            if lib.extension().and_then(|e| e.to_str()) == Some("so") {
+                copy(&lib, android_dir.join(lib.file_name().unwrap())).unwrap();
             }
+        }
+    }
 
-            if adaptive_icon_foreground.is_some() || adaptive_icon_background.is_some() || adaptive_icon_monochrome.is_some() {
-
-                let image_foreground = adaptive_icon_foreground.map(|path| image::open(manifest_dir.join(path)).unwrap());
-                let image_background = adaptive_icon_background.map(|path| image::open(manifest_dir.join(path)).unwrap());
-                let image_monochrome = adaptive_icon_monochrome.map(|path| image::open(manifest_dir.join(path)).unwrap());
-
-                for (res, size) in versions {
-                    let dir = res_dir.join(format!("mipmap-{res}"));
-                    std::fs::remove_dir_all(&dir).unwrap();
-                    std::fs::create_dir_all(&dir).unwrap();
-                    let foreground = image_foreground.as_ref().map(|image| resize(image, size, size, FilterType::Gaussian));
-                    let background = image_background.as_ref().map(|image| resize(image, size, size, FilterType::Gaussian));
-                    let monochrome = image_monochrome.as_ref().map(|image| resize(image, size, size, FilterType::Gaussian));
+    copy_android_libs(manifest_path, target_artifacts);
 
-                    if let Some(image) = foreground {
-                        image.save(dir.join("ic_launcher_foreground.png")).unwrap();
-                    }
-                    if let Some(image) = background {
-                        image.save(dir.join("ic_launcher_background.png")).unwrap();
-                    }
-                    if let Some(image) = monochrome {
-                        image.save(dir.join("ic_launcher_monochrome.png")).unwrap();
-                    }
 
-                    let mut xml = String::from(r#"<?xml version="1.0" encoding="utf-8"?><adaptive-icon xmlns:android="http://schemas.android.com/apk/res/android">"#);
-                    if image_foreground.is_some() {
-                        xml += r#"<foreground android:drawable="@mipmap/ic_launcher_foreground" />"#;
-                    }
-                    if image_background.is_some() {
-                        xml += r#"<background android:drawable="@mipmap/ic_launcher_background" />"#;
-                    }
-                    if image_monochrome.is_some() {
-                        xml += r#"<monochrome android:drawable="@mipmap/ic_launcher_monochrome" />"#;
-                    }
-                    xml += r#"</adaptive-icon>"#;
-                    std::fs::write(dir.join("ic_launcher.xml"), xml).unwrap();
-                }
-            }
-        }
+    if create_android_icons(manifest_path) {
+        change_android_project_file(
+            manifest_dir,
+            "app/src/main/AndroidManifest.xml",
+            vec![(
+                "android:icon=\"@mipmap/ic_launcher\"",
+                "android:icon=\"@mipmap/ic_launcher\"\n        android:roundIcon=\"@mipmap/ic_launcher_round\"",
+            )],
+        );
     }
 
     if let Some(permissions) = get_toml_string_array(manifest_path, vec!["package", "metadata", "android", "permissions"]) {
@@ -273,6 +582,50 @@ fn create_android_project(manifest_path: &Path, target_artifacts: &HashMap<Strin
         }
     }
 
+    let activity = get_android_activity(manifest_path).unwrap_or(format!("{appid}.MainActivity"));
+    change_android_project_file(
+        manifest_dir,
+        "app/src/main/AndroidManifest.xml",
+        vec![(
+            "android:name=\"SDLActivity\"",
+            &*format!("android:name=\"{activity}\""),
+        )],
+    );
+
+    if options.split_per_abi {
+        let mut abis: Vec<&str> = target_artifacts
+            .keys()
+            .map(|target| get_target_android_name(target))
+            .collect();
+        abis.sort();
+        let path = manifest_dir.join("target/android-project/app/build.gradle");
+        let gradle = read_to_string(&path).unwrap() + &abi_splits_gradle(&abis, options.universal_apk);
+        write(path, gradle).unwrap();
+    }
+
+    if options.test_app {
+        let path = manifest_dir.join("target/android-project/app/build.gradle");
+        let gradle = read_to_string(&path).unwrap()
+            + &format!(
+                "\nandroid {{\n    defaultConfig {{\n        applicationIdSuffix \"{TEST_APP_ID_SUFFIX}\"\n    }}\n}}\n"
+            );
+        write(path, gradle).unwrap();
+    }
+
+    if options.debuggable {
+        change_android_project_file(
+            manifest_dir,
+            "app/src/main/AndroidManifest.xml",
+            vec![("<application ", "<application android:debuggable=\"true\" ")],
+        );
+    }
+
+    // Overlay user provided sources and resources
+    overlay_android_project_dir(manifest_path, "java_sources", "app/src/main/java");
+    overlay_android_project_dir(manifest_path, "res", "app/src/main/res");
+
+    copy_android_assets(manifest_path);
+
     let apk_output_dir = manifest_dir.join("target/android-project/app/build/outputs/apk");
     if apk_output_dir.exists() {
         std::fs::remove_dir_all(apk_output_dir).unwrap();
@@ -284,22 +637,16 @@ fn change_android_project_file(
     file_name: &str,
     replacements: Vec<(&str, &str)>,
 ) {
-    let mut content = read_to_string(
-        Path::new(&*get_env_var("SDL"))
-            .join("android-project")
-            .join(file_name),
-    )
-    .expect("Unable to read manifest file");
+    // The template was copied into the target dir before, so changes made
+    // by previous calls are kept.
+    let path = manifest_dir.join("target/android-project").join(file_name);
+    let mut content = read_to_string(&path).expect("Unable to read manifest file");
 
     for (from, to) in replacements {
         content = content.replace(from, to);
     }
 
-    write(
-        manifest_dir.join("target/android-project").join(file_name),
-        &content,
-    )
-    .expect("Unable to write file");
+    write(path, &content).expect("Unable to write file");
 }
 
 pub fn sign_android(manifest_path: &Path, ks_file: Option<String>, ks_pass: Option<String>) {
@@ -308,7 +655,7 @@ pub fn sign_android(manifest_path: &Path, ks_file: Option<String>, ks_pass: Opti
 
     // Find android build tools.
     let tool_paths =
-        std::fs::read_dir(Path::new(&*get_env_var("ANDROID_HOME")).join("build-tools")).unwrap();
+        std::fs::read_dir(get_android_sdk_dir(manifest_path).join("build-tools")).unwrap();
     let mut tool_paths: Vec<String> = tool_paths
         .map(|d| {
             d.unwrap()
@@ -323,7 +670,7 @@ pub fn sign_android(manifest_path: &Path, ks_file: Option<String>, ks_pass: Opti
     tool_paths.sort();
     tool_paths.reverse();
     let tools_version = tool_paths[0].clone();
-    println!("Using build-tools: {}", tools_version);
+    status(&format!("Using build-tools: {}", tools_version));
 
     // Determine key file. Generate if needed.
     let (key_file, key_pass) = if let Some(ks_file) = ks_file {
@@ -331,7 +678,7 @@ pub fn sign_android(manifest_path: &Path, ks_file: Option<String>, ks_pass: Opti
     } else {
         let key_path = release_dir.join("app-release.jks");
         if !key_path.exists() {
-            println!("Generating keyfile...");
+            status("Generating keyfile...");
             assert!(Command::new("keytool")
                 .arg("-genkey")
                 .arg("-dname")
@@ -346,6 +693,7 @@ pub fn sign_android(manifest_path: &Path, ks_file: Option<String>, ks_pass: Opti
                 .arg("2048")
                 .arg("-validity")
                 .arg("10000")
+                .stdout(subprocess_stdout())
                 .status()
                 .unwrap()
                 .success());
@@ -357,73 +705,290 @@ pub fn sign_android(manifest_path: &Path, ks_file: Option<String>, ks_pass: Opti
         )
     };
 
-    println!("Using keyfile: {}", key_file);
+    status(&format!("Using keyfile: {}", key_file));
 
-    // Run zipalign.
-    let zipalign_path = Path::new(&*get_env_var("ANDROID_HOME"))
+    let zipalign_path = get_android_sdk_dir(manifest_path)
         .join("build-tools")
         .join(tools_version.clone())
         .join("zipalign");
-
-    assert!(Command::new(zipalign_path)
-        .arg("-v")
-        .arg("-f")
-        .arg("-p")
-        .arg("4")
-        .arg(release_dir.join("app-release-unsigned.apk"))
-        .arg(release_dir.join("app-release-unsigned-aligned.apk"))
-        .status()
-        .unwrap()
-        .success());
-
-    // Run apksigner
-    let apksigner_path = Path::new(&*get_env_var("ANDROID_HOME"))
+    let apksigner_path = get_android_sdk_dir(manifest_path)
         .join("build-tools")
         .join(tools_version)
         .join("apksigner");
 
-    assert!(Command::new(apksigner_path)
-        .arg("sign")
-        .arg("-ks")
-        .arg(key_file)
-        .arg("-ks-pass")
-        .arg(key_pass)
-        .arg("-out")
-        .arg(release_dir.join("app-release.apk"))
-        .arg(release_dir.join("app-release-unsigned-aligned.apk"))
-        .status()
+    // With split APKs there is one unsigned APK per ABI.
+    let mut unsigned_apks: Vec<PathBuf> = read_dir(&release_dir)
         .unwrap()
-        .success());
+        .map(|f| f.unwrap().path())
+        .filter(|f| f.to_string_lossy().ends_with("-unsigned.apk"))
+        .collect();
+    unsigned_apks.sort();
+
+    for unsigned_apk in unsigned_apks {
+        let unsigned = unsigned_apk.to_string_lossy().to_string();
+        let aligned_apk = PathBuf::from(unsigned.replace("-unsigned.apk", "-unsigned-aligned.apk"));
+        let signed_apk = PathBuf::from(unsigned.replace("-unsigned.apk", ".apk"));
+
+        // Run zipalign.
+        assert!(Command::new(&zipalign_path)
+            .arg("-v")
+            .arg("-f")
+            .arg("-p")
+            .arg("4")
+            .arg(&unsigned_apk)
+            .arg(&aligned_apk)
+            .stdout(subprocess_stdout())
+            .status()
+            .unwrap()
+            .success());
+
+        // Run apksigner
+        assert!(Command::new(&apksigner_path)
+            .arg("sign")
+            .arg("-ks")
+            .arg(&key_file)
+            .arg("-ks-pass")
+            .arg(&key_pass)
+            .arg("-out")
+            .arg(&signed_apk)
+            .arg(&aligned_apk)
+            .stdout(subprocess_stdout())
+            .status()
+            .unwrap()
+            .success());
+
+        let abi = get_apk_abi(&signed_apk, BuildProfile::Release);
+        artifact("signed-apk", &signed_apk, abi.as_deref());
+    }
 }
 
 // keytool -android blabla -genkey -v -keystore my-release-key.jks -keyalg RSA -keysize 2048 -validity 10000 -alias my-alias
 // /home/micke/Android/Sdk/build-tools/30.0.3/zipalign -v -p 4 app-release-unsigned.apk app-release-unsigned-aligned.apk
 // /home/micke/Android/Sdk/build-tools/30.0.3/apksigner sign -ks my-release-key.jks -ks-pass pass:android -out app-release.apk app-release-unsigned-aligned.apk
 
+/// File name of the finished APK from the `apk_name` template, which
+/// defaults to `{name}-{version}-{profile}.apk`. `{abi}` is replaced later.
+fn get_apk_name(manifest_path: &Path, build_target: &BuildTarget, profile: BuildProfile) -> String {
+    let name = match build_target {
+        BuildTarget::Bin => get_toml_string(manifest_path, vec!["package", "name"]).unwrap(),
+        BuildTarget::Example(example) => example.clone(),
+    };
+    let version = get_toml_string(manifest_path, vec!["package", "version"])
+        .unwrap_or("0.0.0".to_string());
+
+    get_toml_string(
+        manifest_path,
+        vec!["package", "metadata", "android", "apk_name"],
+    )
+    .unwrap_or("{name}-{version}-{profile}.apk".to_string())
+    .replace("{name}", &name)
+    .replace("{version}", &version)
+    .replace("{profile}", &profile.to_string())
+}
+
+/// The ABI of a split APK, from gradle's `app-<abi>-<profile>.apk` naming.
+/// None for the APK containing all ABIs.
+fn get_apk_abi(apk: &Path, profile: BuildProfile) -> Option<String> {
+    let name = apk.file_name().unwrap().to_string_lossy();
+    let name = name.strip_prefix("app-")?;
+    let name = name
+        .strip_suffix(&format!("-{profile}.apk"))
+        .or_else(|| name.strip_suffix(&format!("-{profile}-unsigned.apk")))?;
+    Some(name.to_string())
+}
+
+/// The finished APKs of the last build, signed in release mode, along with
+/// their ABI for split APKs.
+pub fn get_output_apks(manifest_dir: &Path, profile: BuildProfile) -> Vec<(Option<String>, PathBuf)> {
+    let dir = manifest_dir
+        .join("target/android-project/app/build/outputs/apk")
+        .join(profile.to_string());
+    let mut apks: Vec<(Option<String>, PathBuf)> = read_dir(dir)
+        .expect("No APKs built")
+        .map(|f| f.unwrap().path())
+        .filter(|f| {
+            let name = f.to_string_lossy();
+            name.ends_with(".apk") && !name.contains("-unsigned")
+        })
+        .map(|f| (get_apk_abi(&f, profile), f))
+        .collect();
+    apks.sort();
+    apks
+}
+
+/// Copies the APKs gradle built, signed in release mode, to `out_dir` or
+/// target/apk and returns their paths. Split APKs get the ABI appended to
+/// their name, unless apk_name contains `{abi}`.
+pub fn copy_output_apks(
+    manifest_path: &Path,
+    build_target: &BuildTarget,
+    profile: BuildProfile,
+    out_dir: Option<&str>,
+) -> Vec<PathBuf> {
+    let manifest_dir = manifest_path.parent().unwrap();
+    let out_dir = match out_dir {
+        Some(dir) => PathBuf::from(dir),
+        None => manifest_dir.join("target/apk"),
+    };
+    create_dir_all(&out_dir).expect("Unable to create output dir");
+
+    let apk_name = get_apk_name(manifest_path, build_target, profile);
+    let mut copied = vec![];
+    for (abi, apk) in get_output_apks(manifest_dir, profile) {
+        let name = match abi {
+            None => apk_name.replace("-{abi}", "").replace("{abi}", ""),
+            Some(abi) if apk_name.contains("{abi}") => apk_name.replace("{abi}", &abi),
+            Some(abi) => match apk_name.strip_suffix(".apk") {
+                Some(stem) => format!("{stem}-{abi}.apk"),
+                None => format!("{apk_name}-{abi}"),
+            },
+        };
+
+        let dest = out_dir.join(name);
+        copy(apk, &dest).expect("Unable to copy APK to output dir");
+        copied.push(dest);
+    }
+
+    copied
+}
+
+/// Reports the APKs and bundles gradle produced for the profile.
+fn report_gradle_artifacts(manifest_dir: &Path, profile: BuildProfile) {
+    let outputs = manifest_dir.join("target/android-project/app/build/outputs");
+    for (dir, extension) in [("apk", "apk"), ("bundle", "aab")] {
+        let Ok(files) = read_dir(outputs.join(dir).join(profile.to_string())) else {
+            continue;
+        };
+        let mut files: Vec<PathBuf> = files.map(|f| f.unwrap().path()).collect();
+        files.sort();
+        for file in files {
+            if file.extension().and_then(|e| e.to_str()) != Some(extension) {
+                continue;
+            }
+            let kind = if extension == "aab" {
+                "aab"
+            } else if file.to_string_lossy().ends_with("-unsigned.apk") {
+                "unsigned-apk"
+            } else {
+                "apk"
+            };
+            artifact(kind, &file, get_apk_abi(&file, profile).as_deref());
+        }
+    }
+}
+
 pub fn build_android_project(
     manifest_path: &Path,
     target_artifacts: &HashMap<String, String>,
-    profile: BuildProfile,
-    ks_file: Option<String>,
-    ks_pass: Option<String>,
+    options: &BuildOptions,
+    sdl: &SdlSource,
 ) {
-    let manifest_dir = manifest_path.parent().unwrap();
+    let stage = Stage::start("project");
+    create_android_project(manifest_path, target_artifacts, options, sdl);
+    stage.finish();
 
-    create_android_project(manifest_path, target_artifacts);
+    assemble_android_project(manifest_path, options);
+}
+
+/// Builds the APK from the android project with gradle, and signs it in
+/// release mode.
+pub fn assemble_android_project(manifest_path: &Path, options: &BuildOptions) {
+    let manifest_dir = manifest_path.parent().unwrap();
+    let profile = options.profile;
 
     let gradle_task = match profile {
         BuildProfile::Debug => "assembleDebug",
         BuildProfile::Release => "assembleRelease",
     };
 
+    let stage = Stage::start("gradle");
     assert!(Command::new("./gradlew")
         .args([gradle_task])
         .current_dir(manifest_dir.join("./target/android-project"))
+        .stdout(subprocess_stdout())
         .status()
         .unwrap()
         .success());
+    report_gradle_artifacts(manifest_dir, profile);
+    stage.finish();
 
     if matches!(profile, BuildProfile::Release) {
-        sign_android(manifest_path, ks_file, ks_pass);
+        let stage = Stage::start("sign");
+        sign_android(
+            manifest_path,
+            options.ks_file.clone(),
+            options.ks_pass.clone(),
+        );
+        stage.finish();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// From app/build.gradle of SDL 2.26's android-project.
+    const SDL_BUILD_GRADLE: &str = r#"android {
+    defaultConfig {
+        versionCode 1
+        versionName "1.0"
+        externalNativeBuild {
+            ndkBuild {
+                arguments "APP_PLATFORM=android-16"
+                abiFilters 'armeabi-v7a', 'arm64-v8a', 'x86', 'x86_64'
+            }
+            // cmake {
+            //     arguments "-DANDROID_APP_PLATFORM=android-16", "-DANDROID_STL=c++_static"
+            //     // abiFilters 'armeabi-v7a', 'arm64-v8a', 'x86', 'x86_64'
+            //     abiFilters 'arm64-v8a'
+            // }
+        }
+    }
+    applicationVariants.all { variant ->
+        tasks["merge${variant.name.capitalize()}Assets"]
+            .dependsOn("externalNativeBuild${variant.name.capitalize()}")
+    }
+    if (!project.hasProperty('EXCLUDE_NATIVE_LIBS')) {
+        sourceSets.main {
+            jniLibs.srcDir 'libs'
+        }
+        externalNativeBuild {
+            ndkBuild {
+                path 'jni/Android.mk'
+            }
+            // cmake {
+            //     path 'jni/CMakeLists.txt'
+            // }
+        }
+    }
+    lintOptions {
+        abortOnError false
+    }
+}
+"#;
+
+    #[test]
+    fn sdl_native_build_is_removed_from_gradle() {
+        let mut gradle = remove_gradle_blocks(SDL_BUILD_GRADLE, "externalNativeBuild");
+        gradle = remove_gradle_blocks(&gradle, "applicationVariants.all");
+
+        assert_eq!(
+            gradle,
+            r#"android {
+    defaultConfig {
+        versionCode 1
+        versionName "1.0"
+    }
+    if (!project.hasProperty('EXCLUDE_NATIVE_LIBS')) {
+        sourceSets.main {
+            jniLibs.srcDir 'libs'
+        }
+    }
+    lintOptions {
+        abortOnError false
+    }
+}
+"#
+        );
     }
 }
@@ -0,0 +1,152 @@
+use crate::message::*;
+use crate::util::*;
+use glob::Pattern;
+use std::collections::HashSet;
+use std::fs::{
+    copy, create_dir_all, hard_link, metadata, read_dir, remove_dir_all, remove_file, File,
+};
+use std::path::{Path, PathBuf};
+
+fn collect_files(root: &Path, dir: &Path, files: &mut Vec<PathBuf>) {
+    for entry in read_dir(dir).expect("Unable to read assets dir") {
+        let path = entry.unwrap().path();
+        if path.is_dir() {
+            collect_files(root, &path, files);
+        } else {
+            files.push(path.strip_prefix(root).unwrap().to_path_buf());
+        }
+    }
+}
+
+/// Copies get the modification time of their source, so any change to the
+/// source, even to an older time, shows.
+fn is_up_to_date(src: &Path, dest: &Path) -> bool {
+    let (src, dest) = match (metadata(src), metadata(dest)) {
+        (Ok(src), Ok(dest)) => (src, dest),
+        _ => return false,
+    };
+
+    match (src.modified(), dest.modified()) {
+        (Ok(src_time), Ok(dest_time)) => src.len() == dest.len() && dest_time == src_time,
+        _ => false,
+    }
+}
+
+fn copy_asset(src: &Path, dest: &Path) {
+    copy(src, dest).expect("Unable to copy asset");
+    let modified = metadata(src).and_then(|m| m.modified());
+    // The copy may be read-only like its source. Setting the time only
+    // needs ownership, not write access.
+    if let Ok(modified) = modified {
+        File::open(dest)
+            .and_then(|f| f.set_modified(modified))
+            .expect("Unable to set the modification time of asset");
+    }
+}
+
+fn parse_patterns(patterns: Vec<String>) -> Vec<Pattern> {
+    patterns
+        .iter()
+        .map(|p| Pattern::new(p).unwrap_or_else(|e| panic!("Invalid asset pattern {p}: {e}")))
+        .collect()
+}
+
+/// The directory named by the `assets` metadata key.
+pub fn get_assets_dir(manifest_path: &Path) -> Option<PathBuf> {
+    get_toml_string(
+        manifest_path,
+        vec!["package", "metadata", "android", "assets"],
+    )
+    .map(|assets| manifest_path.parent().unwrap().join(assets))
+}
+
+/// Copies the directory named by the `assets` metadata key into the
+/// android project, so that SDL_RWFromFile can read it from the APK.
+/// Files that have not changed since the last build are left alone.
+pub fn copy_android_assets(manifest_path: &Path) {
+    let manifest_dir = manifest_path.parent().unwrap();
+    let assets_dest = manifest_dir.join("target/android-project/app/src/main/assets");
+
+    let assets = match get_assets_dir(manifest_path) {
+        Some(assets) => assets,
+        None => {
+            if assets_dest.exists() {
+                remove_dir_all(&assets_dest).unwrap();
+            }
+            return;
+        }
+    };
+
+    if !assets.is_dir() {
+        panic!("Assets dir {} does not exist", assets.display());
+    }
+
+    let include = parse_patterns(
+        get_toml_string_array(
+            manifest_path,
+            vec!["package", "metadata", "android", "assets_include"],
+        )
+        .unwrap_or(vec!["**/*".to_string()]),
+    );
+    let exclude = parse_patterns(
+        get_toml_string_array(
+            manifest_path,
+            vec!["package", "metadata", "android", "assets_exclude"],
+        )
+        .unwrap_or_default(),
+    );
+    let hardlink = matches!(
+        get_toml_string(
+            manifest_path,
+            vec!["package", "metadata", "android", "assets_hardlink"],
+        )
+        .as_deref(),
+        Some("true")
+    );
+
+    let mut files = vec![];
+    collect_files(&assets, &assets, &mut files);
+    files.retain(|file| {
+        include.iter().any(|p| p.matches_path(file))
+            && !exclude.iter().any(|p| p.matches_path(file))
+    });
+
+    let mut copied = 0;
+    for file in &files {
+        let src = assets.join(file);
+        let dest = assets_dest.join(file);
+        if is_up_to_date(&src, &dest) {
+            continue;
+        }
+
+        create_dir_all(dest.parent().unwrap()).unwrap();
+        if dest.exists() {
+            remove_file(&dest).unwrap();
+        }
+        if !hardlink || hard_link(&src, &dest).is_err() {
+            copy_asset(&src, &dest);
+        }
+        copied += 1;
+    }
+
+    // Remove assets that were deleted or are excluded now
+    let mut removed = 0;
+    if assets_dest.is_dir() {
+        let wanted: HashSet<&PathBuf> = files.iter().collect();
+        let mut existing = vec![];
+        collect_files(&assets_dest, &assets_dest, &mut existing);
+        for file in existing {
+            if !wanted.contains(&file) {
+                remove_file(assets_dest.join(file)).unwrap();
+                removed += 1;
+            }
+        }
+    }
+
+    status(&format!(
+        "Assets: {} copied, {} unchanged, {} removed",
+        copied,
+        files.len() - copied,
+        removed
+    ));
+}
@@ -1,18 +1,27 @@
+use crate::android_project::{get_sdl_libs_dir, SdlSource};
+use crate::message::*;
+use crate::toolchain::*;
 use crate::util::*;
 use crate::BuildProfile;
-use cargo::core::compiler::{BuildConfig, CompileKind, CompileMode, CompileTarget, Executor};
-use cargo::core::resolver::CliFeatures;
+use cargo::core::compiler::{
+    BuildConfig, CompileKind, CompileMode, CompileTarget, Executor, MessageFormat,
+    RustcTargetData,
+};
+use cargo::core::resolver::features::FeaturesFor;
+use cargo::core::resolver::{CliFeatures, ForceAllTargets, HasDevUnits};
 use cargo::core::{PackageId, Target, TargetKind, Workspace};
 use cargo::ops::{CompileFilter, CompileOptions, FilterRule, LibRule, Packages};
 use cargo::util::Config as CargoConfig;
 use cargo::CargoResult;
 use cargo_util::ProcessBuilder;
 use std::collections::HashMap;
-use std::path::Path;
+use std::env;
+use std::fs::{copy, create_dir_all, remove_dir_all, write};
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 use std::sync::Mutex;
 
-fn get_target_linker(rust_target_name: &str) -> &str {
+pub fn get_target_linker(rust_target_name: &str) -> &str {
     match rust_target_name {
         "aarch64-linux-android" => {
             "toolchains/llvm/prebuilt/linux-x86_64/bin/aarch64-linux-android31-clang"
@@ -29,20 +38,163 @@ fn get_target_linker(rust_target_name: &str) -> &str {
     }
 }
 
+const SDL_MAIN_SHIM: &str = r#"
+#include <android/log.h>
+
+extern int main(int argc, char *argv[]);
+
+__attribute__((visibility("default")))
+int SDL_main(int argc, char *argv[]) {
+    int status = main(argc, argv);
+    __android_log_print(ANDROID_LOG_INFO, "SDL_main", "exit status %d", status);
+    return status;
+}
+"#;
+
+/// Like SDL_MAIN_SHIM, for a test harness packaged in the app. Its stdout
+/// and stderr are sent to logcat with the RustTest tag, an app's output
+/// would be lost otherwise.
+const SDL_TEST_SHIM: &str = r#"
+#include <android/log.h>
+#include <pthread.h>
+#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+#include <unistd.h>
+
+extern int main(int argc, char *argv[]);
+
+static int output_pipe[2];
+static pthread_t output_thread;
+static int output_finished;
+
+static void *log_output(void *arg) {
+    FILE *output = fdopen(output_pipe[0], "r");
+    char line[4096];
+    while (fgets(line, sizeof(line), output)) {
+        line[strcspn(line, "\n")] = 0;
+        __android_log_write(ANDROID_LOG_INFO, "RustTest", line);
+    }
+    fclose(output);
+    return NULL;
+}
+
+// libtest exits the process when tests failed, so this also runs from exit.
+static void finish_output(void) {
+    if (output_finished) {
+        return;
+    }
+    output_finished = 1;
+    fflush(stdout);
+    fflush(stderr);
+    close(1);
+    close(2);
+    pthread_join(output_thread, NULL);
+}
+
+__attribute__((visibility("default")))
+int SDL_main(int argc, char *argv[]) {
+    pipe(output_pipe);
+    dup2(output_pipe[1], 1);
+    dup2(output_pipe[1], 2);
+    close(output_pipe[1]);
+    pthread_create(&output_thread, NULL, log_output, NULL);
+    atexit(finish_output);
+
+    int status = main(argc, argv);
+    finish_output();
+    __android_log_print(ANDROID_LOG_INFO, "SDL_main", "exit status %d", status);
+    exit(status);
+}
+"#;
+
+/// Version script exporting the JNI functions of a statically linked SDL. The
+/// one rustc passes for dylibs hides everything that isn't Rust.
+const SDL_JNI_EXPORTS: &str = "{
+  global:
+    JNI_OnLoad;
+    Java_org_libsdl_*;
+};
+";
+
+/// Compiles a C object exporting SDL_main, which calls the C main function
+/// rustc generates for bin crates and logs its exit status for logcat.
+fn build_sdl_main_shim(out_dir: &str, linker: &str, name: &str, source: &str) -> CargoResult<String> {
+    let src = Path::new(out_dir).join(format!("{name}.c"));
+    let obj = Path::new(out_dir).join(format!("{name}.o"));
+    write(&src, source)?;
+
+    ProcessBuilder::new(linker)
+        .arg("-c")
+        .arg("-fPIC")
+        .arg("-o")
+        .arg(&obj)
+        .arg(&src)
+        .exec()?;
+
+    Ok(obj.into_os_string().into_string().unwrap())
+}
+
 pub struct LibExecutor {
     linkers: HashMap<String, String>,
+    sdl_main_shim: bool,
+    sdl: SdlSource,
+    package_tests: bool,
     out: Arc<Mutex<HashMap<String, String>>>,
+    sdl_libs: Arc<Mutex<HashMap<String, PathBuf>>>,
 }
 
 impl LibExecutor {
-    pub fn new(linkers: HashMap<String, String>) -> Self {
+    pub fn new(
+        linkers: HashMap<String, String>,
+        sdl_main_shim: bool,
+        sdl: SdlSource,
+        package_tests: bool,
+    ) -> Self {
         Self {
             linkers,
+            sdl_main_shim,
+            sdl,
+            package_tests,
             out: Arc::new(Mutex::new(HashMap::new())),
+            sdl_libs: Arc::new(Mutex::new(HashMap::new())),
         }
     }
 }
 
+impl LibExecutor {
+    /// Makes sure SDL is there before linking against it.
+    fn prepare_sdl(&self, args: &[&str], target_triple: &str) -> CargoResult<()> {
+        match &self.sdl {
+            // SDL is built in parallel, but needed from here on for linking.
+            SdlSource::NdkBuild(sdl_build) => {
+                if let Err(message) = sdl_build.wait(target_triple) {
+                    anyhow::bail!(message);
+                }
+            }
+            SdlSource::Crate(crate_sdl) if crate_sdl.static_link => {}
+            // Remember the libSDL2.so sdl2-sys built, it has to be packaged.
+            SdlSource::Crate(_) => {
+                let lib = args
+                    .windows(2)
+                    .filter(|pair| pair[0] == "-L" && pair[1].contains("sdl2-sys"))
+                    .map(|pair| match pair[1].split_once('=') {
+                        Some((_, dir)) => Path::new(dir).join("libSDL2.so"),
+                        None => Path::new(pair[1]).join("libSDL2.so"),
+                    })
+                    .find(|lib| lib.exists());
+                if let Some(lib) = lib {
+                    self.sdl_libs
+                        .lock()
+                        .unwrap()
+                        .insert(target_triple.to_string(), lib);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
 impl Executor for LibExecutor {
     fn exec(
         &self,
@@ -53,8 +205,12 @@ impl Executor for LibExecutor {
         on_stdout_line: &mut dyn FnMut(&str) -> CargoResult<()>,
         on_stderr_line: &mut dyn FnMut(&str) -> CargoResult<()>,
     ) -> CargoResult<()> {
-        if mode == CompileMode::Build
-            && (target.kind() == &TargetKind::Bin || target.kind() == &TargetKind::ExampleBin)
+        // A test harness to package is linked like the bin, with the shim.
+        let package_test = self.package_tests && mode == CompileMode::Test;
+        let sdl_main_shim = self.sdl_main_shim || package_test;
+        if package_test
+            || mode == CompileMode::Build
+                && (target.kind() == &TargetKind::Bin || target.kind() == &TargetKind::ExampleBin)
         {
             let args: Vec<&str> = cmd.get_args().map(|s| s.to_str().unwrap()).collect();
             let mut new_args = vec![];
@@ -62,7 +218,9 @@ impl Executor for LibExecutor {
             let mut out_dir = "".to_string();
             let mut target_triple = "".to_string();
             while i < args.len() {
-                if args[i] == "--crate-type" && args[i + 1] == "bin" {
+                // With the shim, the bin is kept so rustc generates a C main,
+                // and linked as a shared library below.
+                if args[i] == "--crate-type" && args[i + 1] == "bin" && !sdl_main_shim {
                     new_args.push("--crate-type");
                     new_args.push("dylib");
                     i += 2;
@@ -82,12 +240,43 @@ impl Executor for LibExecutor {
                 }
             }
 
+            self.prepare_sdl(&args, &target_triple)?;
+            // Nothing references SDL's JNI functions, keep and export them
+            // anyway.
+            let exports_arg;
+            if matches!(&self.sdl, SdlSource::Crate(crate_sdl) if crate_sdl.static_link) {
+                let exports = Path::new(&out_dir).join("sdl_jni_exports.map");
+                write(&exports, SDL_JNI_EXPORTS)?;
+                exports_arg = format!("link-arg=-Wl,--version-script={}", exports.display());
+                new_args.push("-C");
+                new_args.push("link-arg=-Wl,--undefined=JNI_OnLoad");
+                new_args.push("-C");
+                new_args.push(&*exports_arg);
+            }
+
+            let linker = self.linkers.get(&target_triple).unwrap();
             let mut linker_arg = "linker=".to_string();
-            linker_arg.push_str(self.linkers.get(&target_triple).unwrap());
+            linker_arg.push_str(linker);
 
             new_args.push("-C");
             new_args.push(&*linker_arg);
 
+            let shim_arg;
+            if sdl_main_shim {
+                let shim = if package_test {
+                    build_sdl_main_shim(&out_dir, linker, "sdl_test_shim", SDL_TEST_SHIM)?
+                } else {
+                    build_sdl_main_shim(&out_dir, linker, "sdl_main_shim", SDL_MAIN_SHIM)?
+                };
+                shim_arg = format!("link-arg={shim}");
+                new_args.push("-C");
+                new_args.push("link-arg=-shared");
+                new_args.push("-C");
+                new_args.push(&*shim_arg);
+                new_args.push("-C");
+                new_args.push("link-arg=-llog");
+            }
+
             new_args.push("--cfg");
             new_args.push("mobile");
 
@@ -102,7 +291,27 @@ impl Executor for LibExecutor {
             let p = Path::new(&*out_dir).join(stdout);
             let p = p.into_os_string().into_string().unwrap();
 
-            self.out.lock().unwrap().insert(target_triple, p);
+            // Bins only built for integration tests aren't packaged.
+            if !self.package_tests || package_test {
+                self.out.lock().unwrap().insert(target_triple, p);
+            }
+        } else if mode == CompileMode::Test {
+            // Test harnesses stay executables, to be run with adb shell.
+            let args: Vec<&str> = cmd.get_args().map(|s| s.to_str().unwrap()).collect();
+            let target_triple = args
+                .windows(2)
+                .find(|pair| pair[0] == "--target")
+                .map(|pair| pair[1].to_string())
+                .unwrap();
+            self.prepare_sdl(&args, &target_triple)?;
+
+            let mut cmd = cmd.clone();
+            cmd.arg("-C")
+                .arg(format!("linker={}", self.linkers.get(&target_triple).unwrap()))
+                .arg("--cfg")
+                .arg("mobile");
+            cmd.exec_with_streaming(on_stdout_line, on_stderr_line, false)
+                .map(drop)?;
         } else {
             cmd.exec_with_streaming(on_stdout_line, on_stderr_line, false)
                 .map(drop)?;
@@ -112,6 +321,111 @@ impl Executor for LibExecutor {
     }
 }
 
+/// SDL built by the sdl2-sys crate, with its `bundled` or `static-link`
+/// feature, instead of by ndk-build.
+#[derive(Clone)]
+pub struct CrateSdl {
+    pub static_link: bool,
+    /// SDL's android-project template, if the sdl2-sys package has it.
+    pub android_project: Option<PathBuf>,
+}
+
+/// Checks if sdl2-sys builds SDL itself for the targets. `sdl_from_crate` in
+/// the metadata overrides the detection.
+pub fn find_crate_sdl(manifest_path: &Path, targets: &[&str]) -> Option<CrateSdl> {
+    let setting = get_toml_string(
+        manifest_path,
+        vec!["package", "metadata", "android", "sdl_from_crate"],
+    );
+    if setting.as_deref() == Some("false") {
+        return None;
+    }
+
+    // Resolved like compile() builds: for the Android targets, with the
+    // default features and without dev-dependencies.
+    let cargo_config = CargoConfig::default().unwrap();
+    let workspace = Workspace::new(manifest_path, &cargo_config).unwrap();
+    let kinds: Vec<CompileKind> = targets
+        .iter()
+        .map(|s| CompileKind::Target(CompileTarget::new(s).unwrap()))
+        .collect();
+    let target_data = RustcTargetData::new(&workspace, &kinds).unwrap();
+    let specs = Packages::Packages(Vec::new()).to_package_id_specs(&workspace).unwrap();
+    let resolve = cargo::ops::resolve_ws_with_opts(
+        &workspace,
+        &target_data,
+        &kinds,
+        &CliFeatures::new_all(false),
+        &specs,
+        HasDevUnits::No,
+        ForceAllTargets::No,
+    )
+    .unwrap();
+
+    let crate_sdl = resolve
+        .targeted_resolve
+        .iter()
+        .find(|id| id.name().as_str() == "sdl2-sys")
+        .and_then(|id| {
+            // Not built for Android at all
+            let features = resolve
+                .resolved_features
+                .activated_features_unverified(id, FeaturesFor::NormalOrDev)?;
+            let has_feature = |name: &str| features.iter().any(|f| f.as_str() == name);
+            if !has_feature("bundled") && !has_feature("static-link") {
+                return None;
+            }
+
+            Some(CrateSdl {
+                static_link: has_feature("static-link"),
+                android_project: resolve
+                    .pkg_set
+                    .get_one(id)
+                    .ok()
+                    .map(|package| package.root().join("SDL/android-project"))
+                    .filter(|dir| dir.is_dir()),
+            })
+        });
+
+    if setting.as_deref() == Some("true") && crate_sdl.is_none() {
+        error("sdl_from_crate is set, but sdl2 is not used with the bundled or static-link feature.");
+        fail();
+    }
+
+    crate_sdl
+}
+
+/// Points the cc and cmake crates to the NDK, so sdl2-sys can build SDL for
+/// Android. Variables that are already set are kept.
+fn set_crate_sdl_env(manifest_path: &Path, targets: &[&str]) {
+    let ndk = get_android_ndk_dir(manifest_path);
+    let set_default = |key: &str, value: &Path| {
+        if env::var_os(key).is_none() {
+            env::set_var(key, value);
+        }
+    };
+
+    set_default("ANDROID_NDK_HOME", &ndk);
+    for target in targets {
+        let suffix = target.replace('-', "_");
+        let clang = ndk.join(get_target_linker(target));
+        set_default(&format!("CC_{suffix}"), &clang);
+        set_default(
+            &format!("CXX_{suffix}"),
+            &PathBuf::from(format!("{}++", clang.display())),
+        );
+        set_default(
+            &format!("AR_{suffix}"),
+            &ndk.join("toolchains/llvm/prebuilt/linux-x86_64/bin/llvm-ar"),
+        );
+        set_default(
+            &format!("CMAKE_TOOLCHAIN_FILE_{suffix}"),
+            &ndk.join("build/cmake/android.toolchain.cmake"),
+        );
+    }
+}
+
+#[derive(Clone)]
 pub enum BuildTarget {
     Bin,
     Example(String),
@@ -119,15 +433,133 @@ pub enum BuildTarget {
 
 pub fn build_bin_as_lib(
     manifest_path: &Path,
-    build_target: BuildTarget,
+    build_target: &BuildTarget,
     targets: &Vec<&str>,
     profile: BuildProfile,
+    jobs: u32,
+    sdl: SdlSource,
 ) -> HashMap<String, String> {
+    let filter = CompileFilter::Only {
+        all_targets: false,
+        lib: LibRule::False,
+        bins: match build_target {
+            BuildTarget::Bin => FilterRule::All,
+            BuildTarget::Example(_) => FilterRule::Just(vec![]),
+        },
+        examples: match build_target {
+            BuildTarget::Bin => FilterRule::Just(vec![]),
+            BuildTarget::Example(s) => FilterRule::Just(vec![s.clone()]),
+        },
+        tests: FilterRule::Just(vec![]),
+        benches: FilterRule::Just(vec![]),
+    };
+
+    let (executor, _) = compile(manifest_path, targets, profile, jobs, sdl, Output::Lib, filter);
+    let out = &*executor.out.lock().unwrap();
+    out.clone()
+}
+
+/// The test harness `build_test_lib` builds.
+pub enum TestHarness<'a> {
+    /// The unit tests of the bin or example. `bin` picks the bin if the
+    /// crate has more than one.
+    Unit { bin: Option<&'a str> },
+    /// The integration test with this name.
+    Test(&'a str),
+}
+
+/// Builds the test harness as libmain.so with the SDL_main shim, to be
+/// packaged in an APK like the app.
+pub fn build_test_lib(
+    manifest_path: &Path,
+    build_target: &BuildTarget,
+    harness: &TestHarness,
+    targets: &Vec<&str>,
+    profile: BuildProfile,
+    jobs: u32,
+    sdl: SdlSource,
+) -> HashMap<String, String> {
+    let filter = CompileFilter::Only {
+        all_targets: false,
+        lib: LibRule::False,
+        bins: match (build_target, harness) {
+            (BuildTarget::Bin, TestHarness::Unit { bin: Some(bin) }) => {
+                FilterRule::Just(vec![bin.to_string()])
+            }
+            (BuildTarget::Bin, TestHarness::Unit { bin: None }) => FilterRule::All,
+            _ => FilterRule::Just(vec![]),
+        },
+        examples: match (build_target, harness) {
+            (BuildTarget::Example(s), TestHarness::Unit { .. }) => FilterRule::Just(vec![s.clone()]),
+            _ => FilterRule::Just(vec![]),
+        },
+        tests: FilterRule::Just(match harness {
+            TestHarness::Test(test) => vec![test.to_string()],
+            TestHarness::Unit { .. } => vec![],
+        }),
+        benches: FilterRule::Just(vec![]),
+    };
+
+    let (executor, tests) =
+        compile(manifest_path, targets, profile, jobs, sdl, Output::TestLib, filter);
+    // Only one harness per target can be packaged as libmain.so
+    if tests.len() > targets.len() {
+        error("The crate has more than one bin. Choose the one to test with --bin NAME.");
+        fail();
+    }
+    let out = &*executor.out.lock().unwrap();
+    out.clone()
+}
+
+/// Builds the test harnesses for the targets, like `cargo test --no-run`,
+/// and returns their paths.
+pub fn build_tests(
+    manifest_path: &Path,
+    targets: &Vec<&str>,
+    profile: BuildProfile,
+    jobs: u32,
+    sdl: SdlSource,
+) -> Vec<PathBuf> {
+    let filter = CompileFilter::Default {
+        required_features_filterable: true,
+    };
+
+    let (_, tests) = compile(manifest_path, targets, profile, jobs, sdl, Output::Tests, filter);
+    tests
+}
+
+/// What `compile` builds the Rust code into.
+#[derive(Clone, Copy, PartialEq)]
+enum Output {
+    /// libmain.so from the bin or example.
+    Lib,
+    /// Test harness executables.
+    Tests,
+    /// A test harness as libmain.so.
+    TestLib,
+}
+
+/// Compiles for the targets with LibExecutor. Returns it, with the outputs
+/// of the build, and the test harnesses built.
+fn compile(
+    manifest_path: &Path,
+    targets: &Vec<&str>,
+    profile: BuildProfile,
+    jobs: u32,
+    sdl: SdlSource,
+    output: Output,
+    filter: CompileFilter,
+) -> (Arc<LibExecutor>, Vec<PathBuf>) {
+    let mode = match output {
+        Output::Lib => CompileMode::Build,
+        Output::Tests | Output::TestLib => CompileMode::Test,
+    };
+
     let mut linkers: HashMap<String, String> = HashMap::new();
     for t in targets {
         linkers.insert(
             t.to_string(),
-            Path::new(&*get_env_var("ANDROID_NDK_HOME"))
+            get_android_ndk_dir(manifest_path)
                 .join(get_target_linker(t))
                 .into_os_string()
                 .into_string()
@@ -153,36 +585,32 @@ pub fn build_bin_as_lib(
     let workspace = Workspace::new(manifest_path, &cargo_config).unwrap();
 
     let mut build_config =
-        BuildConfig::new(&cargo_config, None, false, &[], CompileMode::Build).unwrap();
+        BuildConfig::new(&cargo_config, None, false, &[], mode).unwrap();
 
     build_config.requested_profile = match profile {
         BuildProfile::Release => "release",
         BuildProfile::Debug => "dev",
     }.into();
 
+    build_config.jobs = jobs;
     build_config.requested_kinds = targets
         .iter()
         .map(|s| CompileKind::Target(CompileTarget::new(s).unwrap()))
         .collect();
 
+    if is_json() {
+        build_config.message_format = MessageFormat::Json {
+            render_diagnostics: false,
+            short: false,
+            ansi: false,
+        };
+    }
+
     let compile_options = CompileOptions {
         build_config,
         cli_features: CliFeatures::new_all(false),
         spec: Packages::Packages(Vec::new()),
-        filter: CompileFilter::Only {
-            all_targets: false,
-            lib: LibRule::False,
-            bins: match build_target {
-                BuildTarget::Bin => FilterRule::All,
-                BuildTarget::Example(_) => FilterRule::Just(vec![]),
-            },
-            examples: match build_target {
-                BuildTarget::Bin => FilterRule::Just(vec![]),
-                BuildTarget::Example(s) => FilterRule::Just(vec![s]),
-            },
-            tests: FilterRule::Just(vec![]),
-            benches: FilterRule::Just(vec![]),
-        },
+        filter,
         target_rustdoc_args: None,
         target_rustc_args: None,
         target_rustc_crate_types: None,
@@ -190,10 +618,121 @@ pub fn build_bin_as_lib(
         honor_rust_version: true,
     };
 
-    let executor = Arc::new(LibExecutor::new(linkers));
+    let sdl_main_shim = matches!(
+        get_toml_string(
+            manifest_path,
+            vec!["package", "metadata", "android", "sdl_main_shim"],
+        )
+        .as_deref(),
+        Some("true")
+    );
+
+    if matches!(sdl, SdlSource::Crate(_)) {
+        set_crate_sdl_env(manifest_path, targets);
+    }
+
+    let executor = Arc::new(LibExecutor::new(
+        linkers,
+        sdl_main_shim,
+        sdl.clone(),
+        output == Output::TestLib,
+    ));
     let executor_dyn: Arc<dyn Executor> = executor.clone();
-    cargo::ops::compile_with_exec(&workspace, &compile_options, &executor_dyn).unwrap();
+    let compilation = cargo::ops::compile_with_exec(&workspace, &compile_options, &executor_dyn)
+        .unwrap_or_else(|e| {
+            // ndk-build mustn't be left running. If it failed, that's what
+            // made linking fail, and it's reported instead.
+            sdl.wait(targets);
+            error(&format!("{e:#}"));
+            fail()
+        });
 
-    let out = &*executor.out.lock().unwrap();
-    out.clone()
+    // Put the SDL sdl2-sys built where ndk-build would have, so it gets
+    // packaged the same way.
+    if let SdlSource::Crate(crate_sdl) = &sdl {
+        let manifest_dir = manifest_path.parent().unwrap();
+        let sdl_libs = executor.sdl_libs.lock().unwrap();
+        for target in targets {
+            let libs_dir = get_sdl_libs_dir(manifest_dir, target);
+            if libs_dir.exists() {
+                remove_dir_all(&libs_dir).unwrap();
+            }
+            if crate_sdl.static_link {
+                continue;
+            }
+
+            let lib = sdl_libs.get(*target).unwrap_or_else(|| {
+                panic!("Unable to find the libSDL2.so sdl2-sys built for {target}")
+            });
+            create_dir_all(&libs_dir).unwrap();
+            copy(lib, libs_dir.join("libSDL2.so")).unwrap();
+        }
+    }
+
+    let tests = compilation
+        .tests
+        .into_iter()
+        .map(|output| output.path)
+        .collect();
+    (executor, tests)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ANDROID_TARGETS;
+
+    /// A project in `dir` depending on a stand-in sdl2-sys with
+    /// `dependencies`.
+    fn make_project(dir: &Path, dependencies: &str) -> PathBuf {
+        create_dir_all(dir.join("src")).unwrap();
+        create_dir_all(dir.join("sdl2-sys/src")).unwrap();
+        write(dir.join("src/main.rs"), "fn main() {}\n").unwrap();
+        write(dir.join("sdl2-sys/src/lib.rs"), "").unwrap();
+        write(
+            dir.join("sdl2-sys/Cargo.toml"),
+            "[package]\nname = \"sdl2-sys\"\nversion = \"0.35.2\"\nedition = \"2021\"\n\n\
+             [features]\nbundled = []\nstatic-link = []\n",
+        )
+        .unwrap();
+
+        let manifest_path = dir.join("Cargo.toml");
+        write(
+            &manifest_path,
+            format!(
+                "[package]\nname = \"app\"\nversion = \"0.1.0\"\nedition = \"2021\"\n\n\
+                 {dependencies}"
+            ),
+        )
+        .unwrap();
+        manifest_path
+    }
+
+    #[test]
+    fn crate_sdl_is_found_for_android_features_only() {
+        let targets = ANDROID_TARGETS.to_vec();
+
+        let dir = TempDir::new("desktop-bundled");
+        let manifest_path = make_project(
+            dir.path(),
+            "[target.'cfg(not(target_os = \"android\"))'.dependencies]\n\
+             sdl2-sys = { path = \"sdl2-sys\", features = [\"bundled\"] }\n\n\
+             [target.'cfg(target_os = \"android\")'.dependencies]\n\
+             sdl2-sys = { path = \"sdl2-sys\" }\n\n\
+             [dev-dependencies]\n\
+             sdl2-sys = { path = \"sdl2-sys\", features = [\"static-link\"] }\n\n\
+             [features]\nstatic = [\"sdl2-sys/static-link\"]\n",
+        );
+        assert!(find_crate_sdl(&manifest_path, &targets).is_none());
+
+        let dir = TempDir::new("android-bundled");
+        let manifest_path = make_project(
+            dir.path(),
+            "[target.'cfg(target_os = \"android\")'.dependencies]\n\
+             sdl2-sys = { path = \"sdl2-sys\", features = [\"bundled\"] }\n",
+        );
+        let crate_sdl = find_crate_sdl(&manifest_path, &targets).unwrap();
+        assert!(!crate_sdl.static_link);
+        assert!(crate_sdl.android_project.is_none());
+    }
 }
@@ -0,0 +1,264 @@
+use crate::adb::*;
+use crate::android_project::*;
+use crate::message::*;
+use crate::toolchain::*;
+use crate::{build_android, install_android, BuildOptions, BuildProfile};
+use glob::glob;
+use std::collections::HashMap;
+use std::fs::{copy, create_dir_all, write};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::time::{Duration, Instant};
+
+/// Host port forwarded to the app's JDWP, to release it from `am start -D`.
+const JDWP_PORT: &str = "8700";
+
+/// How long `am start -D` may take to bring up the app's process.
+const START_TIMEOUT: Duration = Duration::from_secs(20);
+
+fn get_lldb_arch(android_name: &str) -> &str {
+    match android_name {
+        "arm64-v8a" => "aarch64",
+        "armeabi-v7a" => "arm",
+        "x86" => "i386",
+        "x86_64" => "x86_64",
+        _ => panic!("Unknown ABI: {}", android_name),
+    }
+}
+
+/// The lldb-server the NDK ships for the ABI. Its location depends on the
+/// clang version and, in older NDKs, is under lib64.
+fn find_lldb_server(manifest_path: &Path, android_name: &str) -> PathBuf {
+    let pattern = get_android_ndk_dir(manifest_path)
+        .join("toolchains/llvm/prebuilt/linux-x86_64/lib*/clang/*/lib/linux")
+        .join(get_lldb_arch(android_name))
+        .join("lldb-server");
+
+    glob(&pattern.to_string_lossy())
+        .unwrap()
+        .filter_map(|p| p.ok())
+        .max()
+        .unwrap_or_else(|| panic!("Unable to find lldb-server for {android_name} in the NDK"))
+}
+
+fn find_lldb(manifest_path: &Path) -> PathBuf {
+    let bin = get_android_ndk_dir(manifest_path).join("toolchains/llvm/prebuilt/linux-x86_64/bin");
+    [bin.join("lldb.sh"), bin.join("lldb")]
+        .into_iter()
+        .find(|lldb| lldb.exists())
+        .unwrap_or(PathBuf::from("lldb"))
+}
+
+/// Copies the unstripped libraries under the names they have in the APK, so
+/// lldb finds them in its search path.
+fn collect_symbols(
+    manifest_path: &Path,
+    target: &str,
+    target_artifacts: &HashMap<String, String>,
+) -> PathBuf {
+    let manifest_dir = manifest_path.parent().unwrap();
+    let android_name = get_target_android_name(target);
+    let dir = manifest_dir.join("target/android-debug").join(android_name);
+    create_dir_all(&dir).unwrap();
+
+    copy(&target_artifacts[target], dir.join("libmain.so")).unwrap();
+    let sdl = get_sdl_libs_dir(manifest_dir, target).join("libSDL2.so");
+    if sdl.exists() {
+        copy(sdl, dir.join("libSDL2.so")).unwrap();
+    }
+
+    dir
+}
+
+/// Writes the lldb commands connecting to lldb-server and attaching to the
+/// app. `attach` is the argument for `process attach`.
+fn write_lldb_commands(manifest_path: &Path, socket: &str, symbols: &Path, attach: &str) -> PathBuf {
+    let lldb_commands = manifest_path
+        .parent()
+        .unwrap()
+        .join("target/android-debug/lldb-commands");
+    write(
+        &lldb_commands,
+        format!(
+            "platform select remote-android\n\
+             platform connect unix-abstract-connect://{socket}\n\
+             settings append target.exec-search-paths \"{}\"\n\
+             process attach {attach}\n\
+             continue\n",
+            symbols.display()
+        ),
+    )
+    .unwrap();
+    lldb_commands
+}
+
+fn shell_line(program: &Path, args: &[&str]) -> String {
+    let mut line = shell_quote(&program.to_string_lossy());
+    for arg in args {
+        line.push(' ');
+        line.push_str(&shell_quote(arg));
+    }
+    line
+}
+
+/// Builds and installs a debuggable APK, starts it waiting for a debugger
+/// and attaches lldb to it. With `print_commands` the commands for attaching
+/// are printed instead, for use in an IDE.
+pub fn debug_android(manifest_path: &Path, options: &BuildOptions, print_commands: bool) {
+    if matches!(options.profile, BuildProfile::Release) {
+        std::env::set_var("CARGO_PROFILE_RELEASE_DEBUG", "true");
+    }
+    let target_artifacts = build_android(manifest_path, options);
+    install_android(manifest_path, options);
+
+    let target = get_device_target(manifest_path);
+    let android_name = get_target_android_name(target);
+
+    let adb = get_adb(manifest_path);
+    let appid = get_android_app_id(manifest_path);
+    let activity = format!("{appid}/{}", get_launcher_activity(manifest_path));
+    let app_dir = format!("/data/data/{appid}");
+    let socket = format!("/{appid}/platform.sock");
+    let lldb_server = find_lldb_server(manifest_path, android_name);
+    let symbols = collect_symbols(manifest_path, target, &target_artifacts);
+
+    let setup: Vec<Vec<String>> = vec![
+        vec![
+            "push".to_string(),
+            lldb_server.display().to_string(),
+            "/data/local/tmp/lldb-server".to_string(),
+        ],
+        vec![
+            "shell".to_string(),
+            format!("run-as {appid} cp /data/local/tmp/lldb-server {app_dir}/lldb-server"),
+        ],
+        vec![
+            "shell".to_string(),
+            format!("run-as {appid} chmod 700 {app_dir}/lldb-server"),
+        ],
+        vec!["shell".to_string(), format!("am start -D -n {activity}")],
+    ];
+    let server = format!(
+        "run-as {appid} sh -c {}",
+        shell_quote(&format!(
+            "cd {app_dir} && ./lldb-server platform --server --listen unix-abstract://{socket}"
+        ))
+    );
+    let lldb = find_lldb(manifest_path);
+
+    if print_commands {
+        // The PID isn't known yet, lldb looks the app up by name
+        let lldb_commands =
+            write_lldb_commands(manifest_path, &socket, &symbols, &format!("--name {appid}"));
+        let adb_line = |args: &[&str]| shell_line(&adb, &[&[ADB_DEVICE][..], args].concat());
+        for args in &setup {
+            let args: Vec<&str> = args.iter().map(|a| a.as_str()).collect();
+            println!("{}", adb_line(&args));
+        }
+        println!("{} &", adb_line(&["shell", &server]));
+        println!(
+            "{} forward tcp:{JDWP_PORT} jdwp:$({})",
+            adb_line(&[]),
+            adb_line(&["shell", "pidof", &appid])
+        );
+        println!(
+            "{}",
+            shell_line(&lldb, &["-s", &lldb_commands.to_string_lossy()])
+        );
+        println!("# Once lldb is attached, let the app continue:");
+        println!("echo quit | jdb -attach localhost:{JDWP_PORT}");
+        return;
+    }
+
+    for args in &setup {
+        assert!(adb_command(&adb)
+            .args(args)
+            .stdout(subprocess_stdout())
+            .status()
+            .unwrap()
+            .success());
+    }
+
+    let mut server = adb_command(&adb)
+        .args(["shell", &server])
+        .stdout(subprocess_stdout())
+        .spawn()
+        .expect("Unable to start lldb-server");
+
+    let start = Instant::now();
+    let pid = loop {
+        let output = adb_command(&adb)
+            .args(["shell", "pidof", &appid])
+            .output()
+            .unwrap();
+        let pid = String::from_utf8_lossy(&output.stdout).trim().to_string();
+        if !pid.is_empty() {
+            break pid;
+        }
+        if start.elapsed() > START_TIMEOUT {
+            server.kill().ok();
+            server.wait().ok();
+            error(&format!(
+                "{appid} didn't start within {} seconds. Check the log with `cargo sdl-apk logcat`.",
+                START_TIMEOUT.as_secs()
+            ));
+            fail();
+        }
+        std::thread::sleep(Duration::from_millis(200));
+    };
+    let lldb_commands =
+        write_lldb_commands(manifest_path, &socket, &symbols, &format!("--pid {pid}"));
+    assert!(adb_command(&adb)
+        .args([
+            "forward",
+            &format!("tcp:{JDWP_PORT}"),
+            &format!("jdwp:{pid}")
+        ])
+        .stdout(subprocess_stdout())
+        .status()
+        .unwrap()
+        .success());
+
+    // The app waits for a Java debugger. Attach jdb and quit it once lldb
+    // is attached, so lldb sees the app from the start.
+    let release_adb = adb.clone();
+    let release_pid = pid.clone();
+    std::thread::spawn(move || {
+        loop {
+            let output = adb_command(&release_adb)
+                .args([
+                    "shell",
+                    "grep",
+                    "TracerPid",
+                    &format!("/proc/{release_pid}/status"),
+                ])
+                .output()
+                .unwrap();
+            let tracer = String::from_utf8_lossy(&output.stdout);
+            if tracer.split_whitespace().nth(1).is_some_and(|t| t != "0") {
+                break;
+            }
+            std::thread::sleep(Duration::from_millis(500));
+        }
+        std::thread::sleep(Duration::from_secs(1));
+
+        let mut jdb = Command::new("jdb")
+            .args(["-attach", &format!("localhost:{JDWP_PORT}")])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .spawn()
+            .expect("Unable to run jdb");
+        std::io::Write::write_all(jdb.stdin.as_mut().unwrap(), b"quit\n").ok();
+        jdb.wait().ok();
+    });
+
+    status(&format!("Attaching lldb to {appid} (PID {pid})"));
+    Command::new(lldb)
+        .arg("-s")
+        .arg(&lldb_commands)
+        .status()
+        .expect("Unable to run lldb");
+
+    server.kill().ok();
+    server.wait().ok();
+}
@@ -0,0 +1,324 @@
+use crate::adb::*;
+use crate::android_project::*;
+use crate::build_bin_as_lib::{build_test_lib, build_tests, TestHarness};
+use crate::logcat::*;
+use crate::message::*;
+use crate::toolchain::*;
+use crate::{get_jobs, install_android, start_sdl, BuildOptions};
+use regex::Regex;
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+/// Where the test harnesses and the libraries they need are pushed to.
+const DEVICE_DIR: &str = "/data/local/tmp/cargo-sdl-apk";
+
+fn adb_status(adb: &Path, args: &[&str]) -> bool {
+    adb_command(adb)
+        .args(args)
+        .stdout(subprocess_stdout())
+        .status()
+        .expect("Unable to run adb")
+        .success()
+}
+
+/// Pushes the test harnesses, with libSDL2.so, to the device and runs them
+/// one after the other, like cargo test. Returns the exit status of the
+/// first one that fails, or 0.
+pub fn run_tests_on_device(
+    manifest_path: &Path,
+    adb: &Path,
+    target: &str,
+    tests: &[PathBuf],
+    args: &[String],
+    env: &[String],
+) -> i32 {
+    let manifest_dir = manifest_path.parent().unwrap();
+
+    assert!(adb_status(adb, &["shell", "mkdir", "-p", DEVICE_DIR]));
+    let sdl = get_sdl_libs_dir(manifest_dir, target).join("libSDL2.so");
+    if sdl.exists() {
+        assert!(adb_status(
+            adb,
+            &[
+                "push",
+                &sdl.to_string_lossy(),
+                &format!("{DEVICE_DIR}/libSDL2.so")
+            ]
+        ));
+    }
+
+    for test in tests {
+        let name = test.file_name().unwrap().to_string_lossy();
+        let device_path = format!("{DEVICE_DIR}/{name}");
+        assert!(adb_status(
+            adb,
+            &["push", &test.to_string_lossy(), &device_path]
+        ));
+
+        let mut line = format!("cd {DEVICE_DIR} && env LD_LIBRARY_PATH={DEVICE_DIR}");
+        for variable in env {
+            line.push(' ');
+            line.push_str(&shell_quote(variable));
+        }
+        line.push_str(&format!(" ./{name}"));
+        for arg in args {
+            line.push(' ');
+            line.push_str(&shell_quote(arg));
+        }
+
+        status(&format!("     Running {}", test.display()));
+        let exit_status = adb_command(adb)
+            .args(["shell", &line])
+            .stdout(subprocess_stdout())
+            .status()
+            .expect("Unable to run adb");
+        if !exit_status.success() {
+            let code = exit_status.code().unwrap_or(1);
+            error(&format!(
+                "test failed on the device: {device_path}, exit status {code}"
+            ));
+            return code;
+        }
+    }
+
+    0
+}
+
+/// The test command. Builds the tests for the connected device, runs them
+/// there and exits with their status.
+pub fn test_android(manifest_path: &Path, options: &BuildOptions) {
+    get_android_sdk_dir(manifest_path);
+    get_android_ndk_dir(manifest_path);
+
+    let target = get_device_target(manifest_path);
+    let targets = vec![target];
+
+    let start = Instant::now();
+    let (sdl, jobs) = start_sdl(manifest_path, &targets, options.profile, get_jobs(options));
+
+    let stage = Stage::start("rust");
+    let tests = build_tests(manifest_path, &targets, options.profile, jobs, sdl.clone());
+    sdl.wait(&targets);
+    for test in &tests {
+        artifact("test", test, Some(get_target_android_name(target)));
+    }
+    stage.finish();
+    print_timings(start);
+
+    std::process::exit(run_tests_on_device(
+        manifest_path,
+        &get_adb(manifest_path),
+        target,
+        &tests,
+        &options.app_args,
+        &options.app_env,
+    ));
+}
+
+/// Results of the tests run in the app, collected from libtest's output.
+#[derive(Default)]
+struct TestReport {
+    passed: usize,
+    failed: Vec<String>,
+    ignored: usize,
+    finished: bool,
+}
+
+/// The test command with --in-app. Builds the test harness as libmain.so,
+/// packages it in a test APK, runs that and exits with the tests' status.
+pub fn test_in_app(manifest_path: &Path, options: &BuildOptions, harness: &TestHarness) {
+    get_android_sdk_dir(manifest_path);
+    get_android_ndk_dir(manifest_path);
+
+    let target = get_device_target(manifest_path);
+    let targets = vec![target];
+
+    let start = Instant::now();
+    let (sdl, jobs) = start_sdl(manifest_path, &targets, options.profile, get_jobs(options));
+
+    let stage = Stage::start("rust");
+    let target_artifacts = build_test_lib(
+        manifest_path,
+        &options.build_target,
+        harness,
+        &targets,
+        options.profile,
+        jobs,
+        sdl.clone(),
+    );
+    sdl.wait(&targets);
+    for (target, artifact_path) in &target_artifacts {
+        artifact(
+            "so",
+            Path::new(artifact_path),
+            Some(get_target_android_name(target)),
+        );
+    }
+    stage.finish();
+
+    build_android_project(manifest_path, &target_artifacts, options, &sdl);
+    print_timings(start);
+    install_android(manifest_path, options);
+
+    std::process::exit(run_test_app(manifest_path, options));
+}
+
+/// Starts the installed test APK and reports the tests' results from its
+/// log. Returns the exit status for the test command.
+fn run_test_app(manifest_path: &Path, options: &BuildOptions) -> i32 {
+    let appid = get_android_app_id(manifest_path) + TEST_APP_ID_SUFFIX;
+    let adb = get_adb(manifest_path);
+    let logcat = Logcat::spawn(manifest_path, &appid);
+
+    let activity = format!("{appid}/{}", get_launcher_activity(manifest_path));
+    assert!(adb_command(&adb)
+        .args(["shell", "am", "start", "-W", "-n", &activity])
+        .args(string_array_extra(INTENT_ARGS, &options.app_args))
+        .args(string_array_extra(INTENT_ENV, &options.app_env))
+        .stdout(subprocess_stdout())
+        .status()
+        .unwrap()
+        .success());
+
+    // Besides the test output, only show problems, unless asked for more
+    let problems = LogcatFilter::new(&[], Some("warn")).unwrap();
+    let filter = if options.logcat_filter.tags.is_empty() && options.logcat_filter.min_level == 'V'
+    {
+        &problems
+    } else {
+        &options.logcat_filter
+    };
+
+    let test_line = Regex::new(r"^test (\S+) \.\.\. (ok|FAILED|ignored)").unwrap();
+    let mut report = TestReport::default();
+    let exit_code = logcat.watch(
+        manifest_path,
+        filter,
+        Until::ExitStatus,
+        &mut |tag, message| {
+            if tag != "RustTest" {
+                return false;
+            }
+
+            status(message);
+            if let Some(c) = test_line.captures(message) {
+                match &c[2] {
+                    "ok" => report.passed += 1,
+                    "FAILED" => report.failed.push(c[1].to_string()),
+                    _ => report.ignored += 1,
+                }
+                test_result(&c[1], &c[2].to_lowercase());
+            }
+            if message.starts_with("test result:") {
+                report.finished = true;
+            }
+            true
+        },
+    );
+
+    assert!(adb_status(&adb, &["shell", "am", "force-stop", &appid]));
+
+    if !report.finished {
+        error(&format!(
+            "The tests didn't finish, {appid} exited with status {exit_code}."
+        ));
+        return if exit_code == 0 { 1 } else { exit_code };
+    }
+    status(&format!(
+        "In-app tests: {} passed, {} failed, {} ignored",
+        report.passed,
+        report.failed.len(),
+        report.ignored
+    ));
+    if !report.failed.is_empty() {
+        error(&format!("Failed tests: {}", report.failed.join(", ")));
+        return 101;
+    }
+    exit_code
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::TempDir;
+    use std::fs::{create_dir_all, read_to_string, set_permissions, write};
+    use std::os::unix::fs::PermissionsExt;
+
+    fn write_script(path: &Path, script: &str) {
+        write(path, script).unwrap();
+        set_permissions(path, PermissionsExt::from_mode(0o755)).unwrap();
+    }
+
+    #[test]
+    fn tests_run_on_device() {
+        let dir = TempDir::new("device");
+        let dir = dir.path();
+        let device = dir.join("device");
+        let log = dir.join("adb.log");
+        create_dir_all(&device).unwrap();
+
+        // The device's files are kept in `device`, shell commands run there
+        let adb = dir.join("adb");
+        write_script(
+            &adb,
+            &format!(
+                r#"#!/bin/sh
+echo "$@" >> {log}
+[ "$1" = -d ] || exit 2
+shift
+command=$1
+shift
+case $command in
+push) mkdir -p "{device}$(dirname "$2")" && cp "$1" "{device}$2" ;;
+shell) exec sh -c "$(echo "$*" | sed 's|/data/|{device}/data/|g')" ;;
+*) exit 2 ;;
+esac
+"#,
+                log = log.display(),
+                device = device.display(),
+            ),
+        );
+
+        let manifest_path = dir.join("Cargo.toml");
+        let target = "aarch64-linux-android";
+        let sdl_dir = get_sdl_libs_dir(dir, target);
+        create_dir_all(&sdl_dir).unwrap();
+        write(sdl_dir.join("libSDL2.so"), "").unwrap();
+
+        let mut tests = vec![];
+        for (name, status) in [("first-0123", 0), ("second-4567", 3), ("third-89ab", 0)] {
+            let test = dir.join(name);
+            write_script(
+                &test,
+                &format!(
+                    r#"#!/bin/sh
+{{ echo "LD_LIBRARY_PATH=$LD_LIBRARY_PATH"; echo "GREETING=$GREETING"; for arg in "$@"; do echo "arg=$arg"; done; }} > "$0.out"
+exit {status}
+"#
+                ),
+            );
+            tests.push(test);
+        }
+
+        let args = ["--exact".to_string(), "it's a test".to_string()];
+        let test_env = ["GREETING=hello world".to_string()];
+        let code = run_tests_on_device(&manifest_path, &adb, target, &tests, &args, &test_env);
+
+        // The exit status of the failed test, the next one isn't run
+        assert_eq!(code, 3);
+        let device_dir = format!("{}{DEVICE_DIR}", device.display());
+        assert!(Path::new(&format!("{device_dir}/libSDL2.so")).exists());
+        assert!(!Path::new(&format!("{device_dir}/third-89ab")).exists());
+        assert_eq!(
+            read_to_string(format!("{device_dir}/first-0123.out")).unwrap(),
+            format!("LD_LIBRARY_PATH={device_dir}\nGREETING=hello world\narg=--exact\narg=it's a test\n")
+        );
+
+        let log = read_to_string(&log).unwrap();
+        assert!(log.lines().all(|line| line.starts_with("-d ")));
+        assert!(log.contains(&format!(
+            "-d push {} {DEVICE_DIR}/libSDL2.so",
+            sdl_dir.join("libSDL2.so").display()
+        )));
+    }
+}
@@ -0,0 +1,329 @@
+use crate::adb::get_adb_override;
+use crate::build_bin_as_lib::{find_crate_sdl, get_target_linker};
+use crate::sdl_source::*;
+use crate::toolchain::*;
+use crate::ANDROID_TARGETS;
+use std::fs::read_to_string;
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+#[derive(PartialEq)]
+enum Status {
+    Ok,
+    Warn,
+    Fail,
+}
+
+struct Check {
+    name: String,
+    status: Status,
+    details: String,
+    fix: Option<String>,
+}
+
+impl Check {
+    fn ok(name: &str, details: String) -> Self {
+        Self {
+            name: name.to_string(),
+            status: Status::Ok,
+            details,
+            fix: None,
+        }
+    }
+
+    fn warn(name: &str, details: String, fix: String) -> Self {
+        Self {
+            name: name.to_string(),
+            status: Status::Warn,
+            details,
+            fix: Some(fix),
+        }
+    }
+
+    fn fail(name: &str, details: String, fix: String) -> Self {
+        Self {
+            name: name.to_string(),
+            status: Status::Fail,
+            details,
+            fix: Some(fix),
+        }
+    }
+}
+
+/// Runs a command and returns its stdout and stderr, or None if it can't be
+/// started.
+fn command_output(program: &str, args: &[&str]) -> Option<String> {
+    let output = Command::new(program).args(args).output().ok()?;
+    let mut text = String::from_utf8_lossy(&output.stdout).to_string();
+    text += &String::from_utf8_lossy(&output.stderr);
+    Some(text)
+}
+
+fn check_ndk(checks: &mut Vec<Check>, ndk: &Path) {
+    let version = read_to_string(ndk.join("source.properties"))
+        .ok()
+        .and_then(|props| {
+            props
+                .lines()
+                .filter_map(|line| line.split_once('='))
+                .find(|(key, _)| key.trim() == "Pkg.Revision")
+                .map(|(_, value)| value.trim().to_string())
+        });
+    match version {
+        Some(version) => checks.push(Check::ok("NDK version", version)),
+        None => checks.push(Check::fail(
+            "NDK version",
+            "no Pkg.Revision in source.properties".to_string(),
+            "Point ANDROID_NDK_HOME to an NDK, e.g. $ANDROID_HOME/ndk/<version>".to_string(),
+        )),
+    }
+
+    for target in ANDROID_TARGETS {
+        let linker = ndk.join(get_target_linker(target));
+        let name = format!("clang {target}");
+        if linker.exists() {
+            checks.push(Check::ok(&name, linker.display().to_string()));
+        } else {
+            checks.push(Check::fail(
+                &name,
+                format!("{} not found", linker.display()),
+                "Install an NDK that provides API 31 clang wrappers (r23 or later)".to_string(),
+            ));
+        }
+    }
+}
+
+fn check_rustup_targets(checks: &mut Vec<Check>) {
+    let installed = match command_output("rustup", &["target", "list", "--installed"]) {
+        Some(installed) => installed,
+        None => {
+            checks.push(Check::fail(
+                "rustup",
+                "not found".to_string(),
+                "Install rustup from https://rustup.rs".to_string(),
+            ));
+            return;
+        }
+    };
+
+    for target in ANDROID_TARGETS {
+        let name = format!("rust {target}");
+        if installed.lines().any(|line| line.trim() == target) {
+            checks.push(Check::ok(&name, "installed".to_string()));
+        } else {
+            checks.push(Check::fail(
+                &name,
+                "not installed".to_string(),
+                format!("rustup target add {target}"),
+            ));
+        }
+    }
+}
+
+fn check_sdl(checks: &mut Vec<Check>, sdl: &Path) {
+    let header = read_to_string(sdl.join("include/SDL_version.h")).unwrap_or_default();
+    let define = |name: &str| {
+        header
+            .lines()
+            .find_map(|line| line.strip_prefix(&format!("#define {name} ")))
+            .map(|value| value.trim().to_string())
+    };
+    let version = match (
+        define("SDL_MAJOR_VERSION"),
+        define("SDL_MINOR_VERSION"),
+        define("SDL_PATCHLEVEL"),
+    ) {
+        (Some(major), Some(minor), Some(patch)) => format!("{major}.{minor}.{patch}"),
+        _ => {
+            checks.push(Check::fail(
+                "SDL version",
+                "include/SDL_version.h not found".to_string(),
+                "Point SDL to a checkout of https://github.com/libsdl-org/SDL".to_string(),
+            ));
+            return;
+        }
+    };
+
+    let branch = command_output(
+        "git",
+        &["-C", &sdl.to_string_lossy(), "rev-parse", "--abbrev-ref", "HEAD"],
+    )
+    .map(|branch| branch.trim().to_string())
+    .filter(|branch| !branch.is_empty() && !branch.contains("fatal"))
+    .unwrap_or("unknown branch".to_string());
+
+    let details = format!("{version} ({branch})");
+    if !version.starts_with("2.") {
+        checks.push(Check::fail(
+            "SDL version",
+            details,
+            "git checkout release-2.26.x".to_string(),
+        ));
+    } else if !sdl.join("android-project").is_dir() {
+        checks.push(Check::fail(
+            "SDL version",
+            format!("{details}, no android-project dir"),
+            "Use a full SDL source checkout".to_string(),
+        ));
+    } else if !version.starts_with("2.26.") {
+        checks.push(Check::warn(
+            "SDL version",
+            details,
+            "SDL 2.26 is known to work: git checkout release-2.26.x".to_string(),
+        ));
+    } else {
+        checks.push(Check::ok("SDL version", details));
+    }
+}
+
+fn check_java(checks: &mut Vec<Check>) {
+    let output = match command_output("java", &["-version"]) {
+        Some(output) => output,
+        None => {
+            checks.push(Check::fail(
+                "java",
+                "not found".to_string(),
+                "Install JDK 17 and put java on the PATH".to_string(),
+            ));
+            return;
+        }
+    };
+
+    let version = output
+        .lines()
+        .next()
+        .and_then(|line| line.split('"').nth(1))
+        .unwrap_or("unknown")
+        .to_string();
+    if version == "17" || version.starts_with("17.") {
+        checks.push(Check::ok("java", version));
+    } else {
+        checks.push(Check::warn(
+            "java",
+            version,
+            "Gradle in the SDL template needs JDK 17, set JAVA_HOME to it".to_string(),
+        ));
+    }
+}
+
+fn check_sdk(checks: &mut Vec<Check>, sdk: &Path) {
+    let mut versions: Vec<String> = std::fs::read_dir(sdk.join("build-tools"))
+        .map(|dirs| {
+            dirs.filter_map(|d| d.ok())
+                .map(|d| d.file_name().to_string_lossy().to_string())
+                .collect()
+        })
+        .unwrap_or_default();
+    versions.sort();
+    match versions.last() {
+        Some(version) => checks.push(Check::ok("build-tools", version.clone())),
+        None => checks.push(Check::fail(
+            "build-tools",
+            "not installed".to_string(),
+            "sdkmanager \"build-tools;33.0.2\"".to_string(),
+        )),
+    }
+
+    let adb = get_adb_override().unwrap_or_else(|| sdk.join("platform-tools/adb"));
+    if adb.exists() {
+        checks.push(Check::ok("adb", adb.display().to_string()));
+    } else {
+        checks.push(Check::warn(
+            "adb",
+            "not installed, needed for run".to_string(),
+            "sdkmanager platform-tools".to_string(),
+        ));
+    }
+}
+
+fn check_found_dir(
+    checks: &mut Vec<Check>,
+    name: &str,
+    dir: Option<PathBuf>,
+    fix: &str,
+) -> Option<PathBuf> {
+    match dir {
+        Some(dir) if dir.is_dir() => {
+            checks.push(Check::ok(name, dir.display().to_string()));
+            Some(dir)
+        }
+        Some(dir) => {
+            checks.push(Check::fail(
+                name,
+                format!("{} is not a directory", dir.display()),
+                fix.to_string(),
+            ));
+            None
+        }
+        None => {
+            checks.push(Check::fail(name, "not found".to_string(), fix.to_string()));
+            None
+        }
+    }
+}
+
+/// Checks all prerequisites and prints a table of the results. Exits with
+/// an error if any check failed.
+pub fn doctor(manifest_path: &Path) {
+    let mut checks = vec![];
+
+    if let Some(sdk) = check_found_dir(
+        &mut checks,
+        "Android SDK",
+        find_android_sdk_dir(manifest_path),
+        "export ANDROID_HOME=/path/to/Android/Sdk",
+    ) {
+        check_sdk(&mut checks, &sdk);
+    }
+    if let Some(ndk) = check_found_dir(
+        &mut checks,
+        "Android NDK",
+        find_android_ndk_dir(manifest_path),
+        "sdkmanager --install ndk-bundle, or export ANDROID_NDK_HOME=/path/to/ndk",
+    ) {
+        check_ndk(&mut checks, &ndk);
+    }
+    // sdl2-sys may bring SDL along with its android-project, then the SDL
+    // source isn't needed. Errors resolving the crate are already reported.
+    let crate_sdl_project = if manifest_path.exists() {
+        catch_unwind(AssertUnwindSafe(|| find_crate_sdl(manifest_path, &ANDROID_TARGETS)))
+            .ok()
+            .flatten()
+            .and_then(|crate_sdl| crate_sdl.android_project)
+    } else {
+        None
+    };
+    if let Some(android_project) = crate_sdl_project {
+        checks.push(Check::ok(
+            "SDL",
+            format!("built by sdl2-sys, {}", android_project.display()),
+        ));
+    } else if let Some(sdl) = check_found_dir(
+        &mut checks,
+        "SDL",
+        find_sdl_dir(manifest_path),
+        "Set sdl_version = \"2.26.5\" in [package.metadata.android] and build once, or export SDL=/path/to/SDL",
+    ) {
+        check_sdl(&mut checks, &sdl);
+    }
+    check_rustup_targets(&mut checks);
+    check_java(&mut checks);
+
+    let width = checks.iter().map(|c| c.name.len()).max().unwrap_or(0);
+    for check in &checks {
+        let status = match check.status {
+            Status::Ok => "ok",
+            Status::Warn => "warn",
+            Status::Fail => "FAIL",
+        };
+        println!("{:width$}  {:4}  {}", check.name, status, check.details);
+        if let Some(fix) = &check.fix {
+            println!("{:width$}        fix: {}", "", fix);
+        }
+    }
+
+    if checks.iter().any(|c| c.status == Status::Fail) {
+        std::process::exit(1);
+    }
+}
@@ -0,0 +1,74 @@
+use crate::message::*;
+use crate::scaffold::entry_point_source;
+use crate::toolchain::*;
+use crate::util::*;
+use std::collections::HashMap;
+use std::path::Path;
+use std::process::Command;
+
+enum EntryPoint {
+    Exported,
+    NotAFunction(String),
+    Mangled(String),
+    Missing,
+}
+
+/// Looks up SDL_main in the dynamic symbol table of a shared library.
+fn find_entry_point(nm: &Path, artifact: &str) -> EntryPoint {
+    let output = Command::new(nm)
+        .args(["-D", "--defined-only", artifact])
+        .output()
+        .unwrap_or_else(|e| panic!("Unable to run {}: {e}", nm.display()));
+    assert!(output.status.success(), "Unable to read symbols of {artifact}");
+
+    let mut result = EntryPoint::Missing;
+    for line in String::from_utf8_lossy(&output.stdout).lines() {
+        // <address> <type> <name>
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let (kind, name) = match fields[..] {
+            [_, kind, name] => (kind, name),
+            _ => continue,
+        };
+
+        if name == "SDL_main" {
+            return match kind {
+                "T" | "W" => EntryPoint::Exported,
+                _ => EntryPoint::NotAFunction(kind.to_string()),
+            };
+        }
+        if name.to_lowercase().contains("sdl_main") {
+            result = EntryPoint::Mangled(name.to_string());
+        }
+    }
+
+    result
+}
+
+/// Makes sure every artifact exports an SDL_main function, otherwise the app
+/// would fail at launch when SDL can't find it.
+pub fn verify_sdl_main(manifest_path: &Path, target_artifacts: &HashMap<String, String>) {
+    let nm = get_android_ndk_dir(manifest_path)
+        .join("toolchains/llvm/prebuilt/linux-x86_64/bin/llvm-nm");
+
+    let edition = get_toml_string(manifest_path, vec!["package", "edition"])
+        .unwrap_or("2015".to_string());
+
+    for (target, artifact) in target_artifacts {
+        let problem = match find_entry_point(&nm, artifact) {
+            EntryPoint::Exported => continue,
+            EntryPoint::NotAFunction(kind) => {
+                format!("SDL_main is exported, but is not a function (symbol type {kind})")
+            }
+            EntryPoint::Mangled(name) => format!(
+                "Found {name}, but it is not exported as SDL_main. Make sure it is named SDL_main and has the no_mangle attribute"
+            ),
+            EntryPoint::Missing => "SDL_main is not exported".to_string(),
+        };
+
+        error(&format!("{problem} in {artifact} ({target})."));
+        eprintln!("SDL calls SDL_main to start the app. Add this to your main.rs:");
+        eprintln!("{}", entry_point_source(&edition));
+        eprintln!("Or set sdl_main_shim = true in [package.metadata.android] to use your regular main function.");
+        fail();
+    }
+}
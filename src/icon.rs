@@ -0,0 +1,504 @@
+use crate::message::*;
+use crate::util::*;
+use image::imageops::{crop_imm, overlay, resize, FilterType};
+use image::{DynamicImage, Rgba, RgbaImage};
+use resvg::tiny_skia::{PathSegment, Pixmap, Transform};
+use resvg::usvg;
+use std::path::Path;
+
+/// Launcher icon sizes for legacy (pre API 26) icons.
+const LEGACY_VERSIONS: [(&str, u32); 5] = [
+    ("mdpi", 48),
+    ("hdpi", 72),
+    ("xhdpi", 96),
+    ("xxhdpi", 144),
+    ("xxxhdpi", 192),
+];
+
+/// Adaptive icon layers are 108dp, of which only the inner 72dp are visible.
+const ADAPTIVE_VERSIONS: [(&str, u32); 5] = [
+    ("mdpi", 108),
+    ("hdpi", 162),
+    ("xhdpi", 216),
+    ("xxhdpi", 324),
+    ("xxxhdpi", 432),
+];
+
+/// An icon source. SVGs are rendered at the requested size instead of being
+/// resized from a single bitmap.
+enum IconSource {
+    Raster(DynamicImage),
+    Svg(Box<usvg::Tree>),
+}
+
+impl IconSource {
+    fn open(path: &Path) -> Self {
+        let extension = path.extension().and_then(|ext| ext.to_str());
+        if extension.map(str::to_lowercase).as_deref() == Some("svg") {
+            let data = std::fs::read(path)
+                .unwrap_or_else(|e| panic!("Unable to read {}: {e}", path.display()));
+            let tree = usvg::Tree::from_data(&data, &usvg::Options::default())
+                .unwrap_or_else(|e| panic!("Unable to parse {}: {e}", path.display()));
+            IconSource::Svg(Box::new(tree))
+        } else {
+            IconSource::Raster(image::open(path).unwrap())
+        }
+    }
+
+    fn render(&self, size: u32) -> RgbaImage {
+        match self {
+            IconSource::Raster(image) => resize(image, size, size, FilterType::Lanczos3),
+            IconSource::Svg(tree) => {
+                let mut pixmap = Pixmap::new(size, size).unwrap();
+                let transform = Transform::from_scale(
+                    size as f32 / tree.size().width(),
+                    size as f32 / tree.size().height(),
+                );
+                resvg::render(tree, transform, &mut pixmap.as_mut());
+
+                RgbaImage::from_fn(size, size, |x, y| {
+                    let c = pixmap.pixel(x, y).unwrap().demultiply();
+                    Rgba([c.red(), c.green(), c.blue(), c.alpha()])
+                })
+            }
+        }
+    }
+}
+
+fn vector_color(paint: &usvg::Paint, opacity: f32) -> Option<String> {
+    match paint {
+        usvg::Paint::Color(c) => Some(format!(
+            "#{:02X}{:02X}{:02X}{:02X}",
+            (opacity * 255.0).round() as u8,
+            c.red,
+            c.green,
+            c.blue
+        )),
+        _ => {
+            warning("Gradients and patterns are not supported in vector drawables");
+            None
+        }
+    }
+}
+
+fn write_vector_path(path: &usvg::Path, opacity: f32, out: &mut String) {
+    if !path.is_visible() {
+        return;
+    }
+
+    let transform = path.abs_transform();
+    let data = match path.data().clone().transform(transform) {
+        Some(data) => data,
+        None => return,
+    };
+
+    let mut path_data = vec![];
+    for segment in data.segments() {
+        path_data.push(match segment {
+            PathSegment::MoveTo(p) => format!("M{},{}", p.x, p.y),
+            PathSegment::LineTo(p) => format!("L{},{}", p.x, p.y),
+            PathSegment::QuadTo(p1, p) => format!("Q{},{} {},{}", p1.x, p1.y, p.x, p.y),
+            PathSegment::CubicTo(p1, p2, p) => {
+                format!("C{},{} {},{} {},{}", p1.x, p1.y, p2.x, p2.y, p.x, p.y)
+            }
+            PathSegment::Close => "Z".to_string(),
+        });
+    }
+
+    let mut attributes = vec![format!("android:pathData=\"{}\"", path_data.join(" "))];
+    if let Some(fill) = path.fill() {
+        if let Some(color) = vector_color(fill.paint(), fill.opacity().get() * opacity) {
+            attributes.push(format!("android:fillColor=\"{color}\""));
+            if fill.rule() == usvg::FillRule::EvenOdd {
+                attributes.push("android:fillType=\"evenOdd\"".to_string());
+            }
+        }
+    }
+    if let Some(stroke) = path.stroke() {
+        if let Some(color) = vector_color(stroke.paint(), stroke.opacity().get() * opacity) {
+            let (sx, sy) = transform.get_scale();
+            attributes.push(format!("android:strokeColor=\"{color}\""));
+            attributes.push(format!(
+                "android:strokeWidth=\"{}\"",
+                stroke.width().get() * (sx + sy) / 2.0
+            ));
+            attributes.push(format!(
+                "android:strokeLineCap=\"{}\"",
+                match stroke.linecap() {
+                    usvg::LineCap::Butt => "butt",
+                    usvg::LineCap::Round => "round",
+                    usvg::LineCap::Square => "square",
+                }
+            ));
+            attributes.push(format!(
+                "android:strokeLineJoin=\"{}\"",
+                match stroke.linejoin() {
+                    usvg::LineJoin::Miter | usvg::LineJoin::MiterClip => "miter",
+                    usvg::LineJoin::Round => "round",
+                    usvg::LineJoin::Bevel => "bevel",
+                }
+            ));
+            attributes.push(format!(
+                "android:strokeMiterLimit=\"{}\"",
+                stroke.miterlimit().get()
+            ));
+        }
+    }
+
+    *out += &format!("    <path\n        {} />\n", attributes.join("\n        "));
+}
+
+fn write_vector_group(group: &usvg::Group, opacity: f32, out: &mut String) {
+    let opacity = opacity * group.opacity().get();
+
+    for node in group.children() {
+        match node {
+            usvg::Node::Group(group) => write_vector_group(group, opacity, out),
+            usvg::Node::Path(path) => write_vector_path(path, opacity, out),
+            usvg::Node::Text(text) => write_vector_group(text.flattened(), opacity, out),
+            usvg::Node::Image(_) => {
+                warning("Embedded images are not supported in vector drawables")
+            }
+        }
+    }
+}
+
+/// Converts an SVG to an Android vector drawable sized like an adaptive
+/// icon layer. Only solid fills and strokes are supported.
+fn vector_drawable(tree: &usvg::Tree) -> String {
+    let mut paths = String::new();
+    write_vector_group(tree.root(), 1.0, &mut paths);
+
+    format!(
+        r#"<?xml version="1.0" encoding="utf-8"?>
+<vector xmlns:android="http://schemas.android.com/apk/res/android"
+    android:width="108dp"
+    android:height="108dp"
+    android:viewportWidth="{}"
+    android:viewportHeight="{}">
+{paths}</vector>
+"#,
+        tree.size().width(),
+        tree.size().height()
+    )
+}
+
+fn reset_dir(dir: &Path) {
+    if dir.exists() {
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+    std::fs::create_dir_all(dir).unwrap();
+}
+
+/// Masks the image with a superellipse of the given exponent, 2 being a
+/// circle and 4 a squircle.
+fn superellipse_mask(image: &RgbaImage, exponent: f32) -> RgbaImage {
+    let mut image = image.clone();
+    let radius = image.width() as f32 / 2.0;
+
+    for (x, y, pixel) in image.enumerate_pixels_mut() {
+        let dx = (x as f32 + 0.5 - radius).abs();
+        let dy = (y as f32 + 0.5 - radius).abs();
+        let distance = (dx.powf(exponent) + dy.powf(exponent)).powf(1.0 / exponent);
+        let coverage = (radius - distance + 0.5).clamp(0.0, 1.0);
+        pixel[3] = (pixel[3] as f32 * coverage).round() as u8;
+    }
+
+    image
+}
+
+fn circle_mask(image: &RgbaImage) -> RgbaImage {
+    superellipse_mask(image, 2.0)
+}
+
+fn validate_icon_source(name: &str, source: &IconSource, min_size: u32) {
+    let (width, height) = match source {
+        IconSource::Raster(image) => (image.width() as f32, image.height() as f32),
+        IconSource::Svg(tree) => (tree.size().width(), tree.size().height()),
+    };
+
+    if (width - height).abs() >= 1.0 {
+        warning(&format!("{name} is not square ({width}x{height}), it will be stretched"));
+    }
+    if matches!(source, IconSource::Raster(_)) && (width as u32) < min_size {
+        warning(&format!(
+            "{name} is only {width}x{height}, it will be upscaled to {min_size}x{min_size}"
+        ));
+    }
+}
+
+fn count_pixels(image: &RgbaImage, f: impl Fn(u32, u32, &Rgba<u8>) -> bool) -> usize {
+    image
+        .enumerate_pixels()
+        .filter(|(x, y, p)| f(*x, *y, p))
+        .count()
+}
+
+/// Checks the layers of an adaptive icon, rendered at 1px per dp.
+fn validate_adaptive_layer(name: &str, layer: &RgbaImage) {
+    match name {
+        "foreground" => {
+            // Content outside the 66dp safe zone can be cut off by masks
+            let outside = count_pixels(layer, |x, y, p| {
+                let dx = x as f32 + 0.5 - 54.0;
+                let dy = y as f32 + 0.5 - 54.0;
+                p[3] > 16 && (dx * dx + dy * dy).sqrt() > 33.0
+            });
+            if outside > 0 {
+                warning(&format!("adaptive_icon_foreground has {outside} visible pixels outside the 66dp safe zone, they may be cut off"));
+            }
+        }
+        "background" if count_pixels(layer, |_, _, p| p[3] < 255) > 0 => {
+            warning("adaptive_icon_background is not fully opaque");
+        }
+        "monochrome" => {
+            let colored = count_pixels(layer, |_, _, p| {
+                let max = p[0].max(p[1]).max(p[2]);
+                let min = p[0].min(p[1]).min(p[2]);
+                p[3] > 16 && max - min > 16
+            });
+            if colored > 0 {
+                warning("adaptive_icon_monochrome contains colors, only its alpha channel is used");
+            }
+            if count_pixels(layer, |_, _, p| p[3] < 255) == 0 {
+                warning("adaptive_icon_monochrome has no transparency and will show as a solid square");
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Writes an HTML contact sheet of the legacy icons at each density, as is
+/// and masked like launchers do, to target/android-icon-preview.
+fn write_icon_preview(manifest_dir: &Path, icons: &[(&str, RgbaImage)]) {
+    let dir = manifest_dir.join("target/android-icon-preview");
+    reset_dir(&dir);
+
+    // Superellipse exponent of the mask, if any
+    let masks = [
+        ("square", None),
+        ("circle", Some(2.0)),
+        ("squircle", Some(4.0)),
+    ];
+
+    let row_height = icons.iter().map(|(_, icon)| icon.height()).max().unwrap() + 16;
+    let mut sheet = RgbaImage::from_pixel(
+        icons.iter().map(|(_, icon)| icon.width() + 16).sum::<u32>() + 16,
+        masks.len() as u32 * row_height + 16,
+        Rgba([224, 224, 224, 255]),
+    );
+    let mut rows = String::new();
+    for (row, (mask, exponent)) in masks.iter().enumerate() {
+        rows += &format!("<tr><th>{mask}</th>");
+        let mut x = 16;
+        for (res, icon) in icons {
+            let file = format!("{res}-{mask}.png");
+            let image = match exponent {
+                Some(exponent) => superellipse_mask(icon, *exponent),
+                None => icon.clone(),
+            };
+            image.save(dir.join(&file)).unwrap();
+            overlay(
+                &mut sheet,
+                &image,
+                x as i64,
+                (16 + row as u32 * row_height) as i64,
+            );
+            x += icon.width() + 16;
+            rows += &format!(r#"<td><img src="{file}"></td>"#);
+        }
+        rows += "</tr>\n";
+    }
+    sheet.save(dir.join("contact-sheet.png")).unwrap();
+
+    let header: String = icons
+        .iter()
+        .map(|(res, icon)| format!("<th>{res} ({}px)</th>", icon.width()))
+        .collect();
+    std::fs::write(
+        dir.join("index.html"),
+        format!(
+            r#"<!DOCTYPE html>
+<html>
+<head><title>Launcher icon preview</title></head>
+<body style="background: #e0e0e0; font-family: sans-serif">
+<table>
+<tr><th></th>{header}</tr>
+{rows}</table>
+</body>
+</html>
+"#
+        ),
+    )
+    .unwrap();
+
+    status(&format!("Icon preview: {}", dir.join("index.html").display()));
+}
+
+fn save_legacy_icons(dir: &Path, image: &RgbaImage) {
+    image.save(dir.join("ic_launcher.png")).unwrap();
+    circle_mask(image)
+        .save(dir.join("ic_launcher_round.png"))
+        .unwrap();
+}
+
+/// Writes the launcher icons configured in `[package.metadata.android]` into
+/// the android project. Returns false if no icon is configured and the
+/// template's icon is kept.
+pub fn create_android_icons(manifest_path: &Path) -> bool {
+    let manifest_dir = manifest_path.parent().unwrap();
+
+    let icon = get_toml_string(
+        manifest_path,
+        vec!["package", "metadata", "android", "icon"],
+    );
+    let adaptive_icon_foreground = get_toml_string(
+        manifest_path,
+        vec!["package", "metadata", "android", "adaptive_icon_foreground"],
+    );
+    let adaptive_icon_background = get_toml_string(
+        manifest_path,
+        vec!["package", "metadata", "android", "adaptive_icon_background"],
+    );
+    let adaptive_icon_monochrome = get_toml_string(
+        manifest_path,
+        vec!["package", "metadata", "android", "adaptive_icon_monochrome"],
+    );
+    let adaptive = adaptive_icon_foreground.is_some()
+        || adaptive_icon_background.is_some()
+        || adaptive_icon_monochrome.is_some();
+    if adaptive && icon.is_some() {
+        panic!("You can only specify an icon OR an adaptive icon");
+    }
+
+    let res_dir = manifest_dir.join("target/android-project/app/src/main/res");
+    let anydpi_dir = res_dir.join("mipmap-anydpi-v26");
+    if !adaptive && anydpi_dir.exists() {
+        std::fs::remove_dir_all(&anydpi_dir).unwrap();
+    }
+
+    if let Some(icon) = icon {
+        let image = IconSource::open(&manifest_dir.join(icon));
+        validate_icon_source("icon", &image, 192);
+
+        let corners = image.render(192);
+        if [(0, 0), (191, 0), (0, 191), (191, 191)]
+            .iter()
+            .any(|(x, y)| corners.get_pixel(*x, *y)[3] < 255)
+        {
+            warning("icon has a transparent background, launchers may put it on a white background. Consider an adaptive icon");
+        }
+
+        let mut previews = vec![];
+        for (res, size) in LEGACY_VERSIONS {
+            let dir = res_dir.join(format!("mipmap-{res}"));
+            reset_dir(&dir);
+            let legacy = image.render(size);
+            save_legacy_icons(&dir, &legacy);
+            previews.push((res, legacy));
+        }
+        write_icon_preview(manifest_dir, &previews);
+
+        return true;
+    }
+
+    if !adaptive {
+        return false;
+    }
+
+    let vector = matches!(
+        get_toml_string(
+            manifest_path,
+            vec!["package", "metadata", "android", "adaptive_icon_vector"],
+        )
+        .as_deref(),
+        Some("true")
+    );
+
+    let open = |path: Option<String>| path.map(|path| IconSource::open(&manifest_dir.join(path)));
+    let layers = [
+        ("background", open(adaptive_icon_background)),
+        ("foreground", open(adaptive_icon_foreground)),
+        ("monochrome", open(adaptive_icon_monochrome)),
+    ];
+    for (name, source) in &layers {
+        if let Some(source) = source {
+            validate_icon_source(&format!("adaptive_icon_{name}"), source, 432);
+            validate_adaptive_layer(name, &source.render(108));
+        }
+    }
+
+    // Foreground and monochrome SVGs can be used as vector drawables,
+    // other layers are rendered for each density.
+    let drawable_dir = res_dir.join("drawable");
+    let mut drawables = vec![];
+    for (name, source) in &layers {
+        let path = drawable_dir.join(format!("ic_launcher_{name}.xml"));
+        match source {
+            Some(IconSource::Svg(tree)) if vector && *name != "background" => {
+                std::fs::create_dir_all(&drawable_dir).unwrap();
+                std::fs::write(path, vector_drawable(tree)).unwrap();
+                drawables.push(*name);
+            }
+            _ => {
+                if path.exists() {
+                    std::fs::remove_file(path).unwrap();
+                }
+            }
+        }
+    }
+
+    let mut previews = vec![];
+    for ((res, size), (_, legacy_size)) in ADAPTIVE_VERSIONS.into_iter().zip(LEGACY_VERSIONS) {
+        let dir = res_dir.join(format!("mipmap-{res}"));
+        reset_dir(&dir);
+
+        let mut rendered = vec![];
+        for (name, source) in &layers {
+            if let Some(source) = source {
+                let layer = source.render(size);
+                if !drawables.contains(name) {
+                    layer
+                        .save(dir.join(format!("ic_launcher_{name}.png")))
+                        .unwrap();
+                }
+                rendered.push((*name, layer));
+            }
+        }
+
+        // Legacy fallback: the visible part of the layers, composited
+        let mut legacy = RgbaImage::new(size, size);
+        for (name, layer) in &rendered {
+            if *name != "monochrome" {
+                overlay(&mut legacy, layer, 0, 0);
+            }
+        }
+        let inset = size / 6;
+        let legacy = crop_imm(&legacy, inset, inset, size - 2 * inset, size - 2 * inset).to_image();
+        let legacy = resize(&legacy, legacy_size, legacy_size, FilterType::Lanczos3);
+        save_legacy_icons(&dir, &legacy);
+        previews.push((res, legacy));
+    }
+    write_icon_preview(manifest_dir, &previews);
+
+    let mut xml = String::from(
+        r#"<?xml version="1.0" encoding="utf-8"?><adaptive-icon xmlns:android="http://schemas.android.com/apk/res/android">"#,
+    );
+    for (name, source) in &layers {
+        if source.is_some() {
+            let kind = if drawables.contains(name) {
+                "drawable"
+            } else {
+                "mipmap"
+            };
+            xml += &format!(r#"<{name} android:drawable="@{kind}/ic_launcher_{name}" />"#);
+        }
+    }
+    xml += r#"</adaptive-icon>"#;
+
+    reset_dir(&anydpi_dir);
+    std::fs::write(anydpi_dir.join("ic_launcher.xml"), &xml).unwrap();
+    std::fs::write(anydpi_dir.join("ic_launcher_round.xml"), &xml).unwrap();
+
+    true
+}
@@ -0,0 +1,286 @@
+use crate::adb::*;
+use crate::android_project::get_android_app_id;
+use crate::message::*;
+use regex::Regex;
+use std::io::{BufRead, BufReader, IsTerminal};
+use std::path::Path;
+use std::process::{Child, Stdio};
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError};
+use std::time::{Duration, Instant};
+
+/// How long to wait for the app to come back after its process died.
+const RESTART_GRACE: Duration = Duration::from_secs(3);
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+const LEVELS: &str = "VDIWEFA";
+
+pub struct LogcatFilter {
+    pub tags: Vec<String>,
+    pub min_level: char,
+}
+
+impl LogcatFilter {
+    /// `tags` may contain comma separated lists. `level` is a logcat level
+    /// letter or name, like `w` or `warn`. Fails on an unknown level.
+    pub fn new(tags: &[String], level: Option<&str>) -> Result<Self, String> {
+        let min_level = match level {
+            None => 'V',
+            Some(level) => match &*level.to_ascii_lowercase() {
+                "v" | "verbose" => 'V',
+                "d" | "debug" => 'D',
+                "i" | "info" => 'I',
+                "w" | "warn" => 'W',
+                "e" | "error" => 'E',
+                "f" | "fatal" => 'F',
+                "a" | "assert" => 'A',
+                _ => return Err(format!("Unknown log level: {level}")),
+            },
+        };
+
+        Ok(Self {
+            tags: tags
+                .iter()
+                .flat_map(|tags| tags.split(','))
+                .map(|tag| tag.trim().to_string())
+                .filter(|tag| !tag.is_empty())
+                .collect(),
+            min_level,
+        })
+    }
+
+    fn matches(&self, level: char, tag: &str) -> bool {
+        LEVELS.find(level).unwrap_or(0) >= LEVELS.find(self.min_level).unwrap()
+            && (self.tags.is_empty() || self.tags.iter().any(|t| t == tag))
+    }
+}
+
+/// When `Logcat::watch` returns.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Until {
+    /// The app exited and didn't come back.
+    AppExited,
+    /// The app logged its exit status.
+    ExitStatus,
+    /// Never, the app's restarts are followed for as long as the tool runs.
+    Forever,
+}
+
+struct LogLine {
+    pid: u32,
+    level: char,
+    tag: String,
+    message: String,
+}
+
+/// A running `adb logcat`, started before the app so no output is missed.
+pub struct Logcat {
+    appid: String,
+    adb: Child,
+    lines: Receiver<LogLine>,
+}
+
+impl Logcat {
+    pub fn spawn(manifest_path: &Path, appid: &str) -> Self {
+        let mut adb = adb_command(&get_adb(manifest_path))
+            .args(["logcat", "-v", "threadtime", "-T", "1"])
+            .stdout(Stdio::piped())
+            .spawn()
+            .expect("Unable to run adb logcat");
+
+        // <date> <time> <pid> <tid> <level> <tag>: <message>
+        let format =
+            Regex::new(r"^\S+\s+\S+\s+(\d+)\s+\d+\s+([VDIWEFA])\s+(.*?)\s*: (.*)$").unwrap();
+        let stdout = adb.stdout.take().unwrap();
+        let (sender, lines) = channel();
+        std::thread::spawn(move || {
+            for line in BufReader::new(stdout).lines() {
+                let Ok(line) = line else { break };
+                let Some(c) = format.captures(&line) else {
+                    continue;
+                };
+                let line = LogLine {
+                    pid: c[1].parse().unwrap(),
+                    level: c[2].chars().next().unwrap(),
+                    tag: c[3].to_string(),
+                    message: c[4].to_string(),
+                };
+                if sender.send(line).is_err() {
+                    break;
+                }
+            }
+        });
+
+        Self {
+            appid: appid.to_string(),
+            adb,
+            lines,
+        }
+    }
+
+    fn app_pid(&self, manifest_path: &Path) -> Option<u32> {
+        let output = adb_command(&get_adb(manifest_path))
+            .args(["shell", "pidof", &self.appid])
+            .output()
+            .ok()?;
+        String::from_utf8_lossy(&output.stdout)
+            .split_whitespace()
+            .next()?
+            .parse()
+            .ok()
+    }
+
+    /// Prints the app's log until it exits, following it across restarts,
+    /// and returns its exit status.
+    pub fn follow(self, manifest_path: &Path, filter: &LogcatFilter) -> i32 {
+        self.watch(manifest_path, filter, Until::AppExited, &mut |_, _| false)
+    }
+
+    /// Like `follow`, but each of the app's lines is first passed to
+    /// `on_line` with its tag, and only printed if that returns false.
+    pub fn watch(
+        mut self,
+        manifest_path: &Path,
+        filter: &LogcatFilter,
+        until: Until,
+        on_line: &mut dyn FnMut(&str, &str) -> bool,
+    ) -> i32 {
+        let start_proc = Regex::new(r"^Start proc (\d+):([^/\s]+)").unwrap();
+        let exit_status = Regex::new(r"^exit status (-?\d+)").unwrap();
+        let color = std::io::stdout().is_terminal() && !is_json();
+
+        let mut pid = self.app_pid(manifest_path);
+        let mut died_at: Option<Instant> = None;
+        let mut last_poll = Instant::now();
+        let mut exit_code = None;
+        let mut crashed = false;
+
+        match pid {
+            Some(pid) => status_line(&format!("Following {} (PID {pid})", self.appid)),
+            None => status_line(&format!("Waiting for {} to start...", self.appid)),
+        }
+
+        loop {
+            match self.lines.recv_timeout(POLL_INTERVAL) {
+                Ok(line) => {
+                    // Catch restarts without waiting for the next poll.
+                    if let Some(c) = start_proc.captures(&line.message) {
+                        if c[2] == self.appid {
+                            let new_pid = c[1].parse().unwrap();
+                            if pid.is_some() {
+                                status_line(&format!(
+                                    "{} restarted with PID {new_pid}",
+                                    self.appid
+                                ));
+                            } else {
+                                status_line(&format!("Following {} (PID {new_pid})", self.appid));
+                            }
+                            pid = Some(new_pid);
+                            died_at = None;
+                            exit_code = None;
+                            crashed = false;
+                        }
+                    }
+
+                    if Some(line.pid) != pid {
+                        continue;
+                    }
+
+                    let panic = line.message.contains("panicked at");
+                    if panic || line.level == 'F' || line.message.starts_with("FATAL EXCEPTION") {
+                        crashed = true;
+                    }
+
+                    if !on_line(&line.tag, &line.message) && filter.matches(line.level, &line.tag) {
+                        print_line(&line, color, panic);
+                    }
+
+                    if line.tag == "SDL_main" {
+                        if let Some(c) = exit_status.captures(&line.message) {
+                            exit_code = c[1].parse().ok();
+                            if until == Until::ExitStatus {
+                                self.adb.kill().ok();
+                                return exit_code.unwrap();
+                            }
+                        }
+                    }
+                }
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => {
+                    error("adb logcat stopped.");
+                    return 1;
+                }
+            }
+
+            if last_poll.elapsed() < POLL_INTERVAL {
+                continue;
+            }
+            last_poll = Instant::now();
+
+            match (self.app_pid(manifest_path), pid) {
+                (Some(current), Some(known)) if current == known => died_at = None,
+                (Some(current), known) => {
+                    if known.is_some() {
+                        status_line(&format!("{} restarted with PID {current}", self.appid));
+                    } else {
+                        status_line(&format!("Following {} (PID {current})", self.appid));
+                    }
+                    pid = Some(current);
+                    died_at = None;
+                    exit_code = None;
+                    crashed = false;
+                }
+                (None, Some(_)) => {
+                    let died_at = *died_at.get_or_insert(Instant::now());
+                    if died_at.elapsed() >= RESTART_GRACE {
+                        let exit_code = exit_code.unwrap_or(if crashed { 1 } else { 0 });
+                        status_line(&format!("{} exited with status {exit_code}", self.appid));
+                        if until == Until::Forever {
+                            pid = None;
+                            continue;
+                        }
+                        self.adb.kill().ok();
+                        return exit_code;
+                    }
+                }
+                (None, None) => {}
+            }
+        }
+    }
+}
+
+fn status_line(message: &str) {
+    status(&format!("--- {message}"));
+}
+
+fn print_line(line: &LogLine, color: bool, panic: bool) {
+    if !color {
+        status(&format!("{} {}: {}", line.level, line.tag, line.message));
+        return;
+    }
+
+    let level_color = match line.level {
+        'V' => "37",
+        'D' => "34",
+        'I' => "32",
+        'W' => "33",
+        _ => "31",
+    };
+    if panic {
+        println!(
+            "\x1b[{level_color}m{} {}:\x1b[0m \x1b[1;31m{}\x1b[0m",
+            line.level, line.tag, line.message
+        );
+    } else {
+        println!(
+            "\x1b[{level_color}m{} {}:\x1b[0m {}",
+            line.level, line.tag, line.message
+        );
+    }
+}
+
+/// The standalone logcat command.
+pub fn logcat(manifest_path: &Path, filter: &LogcatFilter) {
+    let status = Logcat::spawn(manifest_path, &get_android_app_id(manifest_path))
+        .follow(manifest_path, filter);
+    std::process::exit(status);
+}
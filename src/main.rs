@@ -1,16 +1,61 @@
+use std::collections::HashMap;
+use std::ffi::OsString;
 use std::fs::canonicalize;
+use std::panic::{catch_unwind, resume_unwind, AssertUnwindSafe};
 use std::path::Path;
-use std::process::Command;
+use std::time::Instant;
 
 mod build_bin_as_lib;
 use build_bin_as_lib::*;
 
 mod util;
-use util::*;
+
+mod adb;
+use adb::*;
+
+mod logcat;
+use logcat::*;
+
+mod debug;
+use debug::*;
+
+mod device_test;
+use device_test::*;
+
+mod watch;
+use watch::*;
+
+mod toolchain;
+use toolchain::*;
+
+mod sdl_source;
+use sdl_source::*;
 
 mod android_project;
 use android_project::*;
 
+mod assets;
+
+mod icon;
+
+mod doctor;
+use doctor::*;
+
+mod scaffold;
+use scaffold::*;
+
+mod entry_point;
+use entry_point::*;
+
+mod message;
+use message::*;
+
+pub const ANDROID_TARGETS: [&str; 3] = [
+    "aarch64-linux-android",
+    "armv7-linux-androideabi",
+    "i686-linux-android",
+];
+
 #[derive(Clone, Copy)]
 pub enum BuildProfile {
     Debug,
@@ -30,11 +75,23 @@ const HELP: &str = "
 cargo-sdl-apk -- Build APKs with Rust and SDL.
 
 USAGE:
-  cargo sdl-apk <command> [OPTIONS] 
+  cargo sdl-apk <command> [OPTIONS] [-- APP_ARGS]
 
 COMMANDS:
   build                 Build APK from bin target.
   run                   Build APK and run using adb.
+  watch                 Like run, then rebuild, reinstall and restart the
+                        app whenever src/, the assets or Cargo.toml change.
+  test                  Build the tests and run them on the device. APP_ARGS
+                        are passed to the test harness. Exits with the
+                        tests' status.
+  debug                 Build a debuggable APK, start it and attach lldb.
+  logcat                Show the app's log, following it across restarts.
+                        Exits with the app's exit status.
+  doctor                Check that all prerequisites are installed.
+  new NAME              Create a new SDL Android project.
+  init                  Set up an existing crate, or the current directory,
+                        for Android.
 
 OPTIONS:
   --manifest-path PATH  Path to Cargo.toml.
@@ -43,20 +100,86 @@ OPTIONS:
   --ks FILE             Keystore file for signing. If omitted a self signed
                         key with pass 'android' will be generated.
   --ks-pass pass:PASS   Keystore pass. Only required if key file is specified.
+  --out-dir DIR         Where the finished APK is copied to, named after
+                        apk_name. Defaults to target/apk.
+  --split-per-abi       Build one APK per ABI, with distinct version codes.
+                        run installs the one matching the device.
+  --universal-apk       With --split-per-abi, also build an APK containing
+                        all ABIs.
+  -j, --jobs N          Number of parallel jobs, shared by the SDL and Rust
+                        builds. Defaults to the number of CPUs.
+  --env KEY=VALUE       Environment variable for the app started by run, or
+                        the tests. Can be repeated.
+  --tag TAG             Only show log lines with this tag, for run, watch
+                        and logcat. Can be repeated.
+  --level LEVEL         Only show log lines of this level or higher:
+                        verbose, debug, info, warn, error, fatal or assert.
+  --in-app              For test, package the tests in an APK and run them
+                        in the app, with a window and the assets.
+  --test NAME           For test --in-app, the integration test to run
+                        instead of the bin's or example's unit tests.
+  --bin NAME            For test --in-app, the bin whose unit tests are run,
+                        if the crate has more than one.
+  --print-commands      For debug, print the commands to attach a debugger,
+                        for IDEs, instead of starting lldb.
+  --message-format FMT  Output format, human (default) or json. With json
+                        stdout carries one JSON object per line for stages,
+                        artifacts, warnings and errors, along with cargo's
+                        own JSON messages.
 ";
 
+pub struct BuildOptions {
+    pub build_target: BuildTarget,
+    pub profile: BuildProfile,
+    pub ks_file: Option<String>,
+    pub ks_pass: Option<String>,
+    pub out_dir: Option<String>,
+    pub split_per_abi: bool,
+    pub universal_apk: bool,
+    pub jobs: Option<u32>,
+    pub logcat_filter: LogcatFilter,
+    pub app_args: Vec<String>,
+    pub app_env: Vec<String>,
+    pub debuggable: bool,
+    pub test_app: bool,
+}
+
 #[derive(Debug)]
 struct SdlApkArgs {
     manifest_path: String,
     command: String,
+    name: Option<String>,
     example: Option<String>,
     release: bool,
     ks_file: Option<String>,
     ks_pass: Option<String>,
+    message_format: Option<String>,
+    out_dir: Option<String>,
+    split_per_abi: bool,
+    universal_apk: bool,
+    jobs: Option<u32>,
+    tags: Vec<String>,
+    level: Option<String>,
+    app_args: Vec<String>,
+    app_env: Vec<String>,
+    print_commands: bool,
+    in_app: bool,
+    test: Option<String>,
+    bin: Option<String>,
 }
 
 fn parse_args() -> Result<SdlApkArgs, pico_args::Error> {
-    let mut pargs = pico_args::Arguments::from_env();
+    // Everything after -- is passed to the app by run
+    let mut env_args: Vec<OsString> = std::env::args_os().skip(1).collect();
+    let app_args = match env_args.iter().position(|arg| arg == "--") {
+        Some(i) => {
+            let app_args = env_args.split_off(i + 1);
+            env_args.pop();
+            app_args
+        }
+        None => vec![],
+    };
+    let mut pargs = pico_args::Arguments::from_vec(env_args);
 
     let mut cmd = pargs.free_from_str()?;
     if cmd == "sdl-apk" {
@@ -71,6 +194,29 @@ fn parse_args() -> Result<SdlApkArgs, pico_args::Error> {
         ks_file: pargs.opt_value_from_str("--ks-file")?,
         ks_pass: pargs.opt_value_from_str("--ks-pass")?,
         release: pargs.contains("--release"),
+        message_format: pargs.opt_value_from_str("--message-format")?,
+        out_dir: pargs.opt_value_from_str("--out-dir")?,
+        split_per_abi: pargs.contains("--split-per-abi"),
+        universal_apk: pargs.contains("--universal-apk"),
+        jobs: pargs.opt_value_from_str(["-j", "--jobs"])?,
+        tags: pargs.values_from_str("--tag")?,
+        level: pargs.opt_value_from_str("--level")?,
+        app_env: pargs.values_from_str("--env")?,
+        print_commands: pargs.contains("--print-commands"),
+        in_app: pargs.contains("--in-app"),
+        test: pargs.opt_value_from_str("--test")?,
+        bin: pargs.opt_value_from_str("--bin")?,
+        app_args: app_args
+            .into_iter()
+            .map(|arg| arg.into_string())
+            .collect::<Result<_, _>>()
+            .map_err(|_| pico_args::Error::NonUtf8Argument)?,
+        // Only new takes a free argument, for other commands it's unknown
+        name: if cmd == "new" {
+            pargs.opt_free_from_str()?
+        } else {
+            None
+        },
         command: cmd,
     };
 
@@ -84,101 +230,165 @@ fn parse_args() -> Result<SdlApkArgs, pico_args::Error> {
     Ok(args)
 }
 
-fn build_android(
+fn get_jobs(options: &BuildOptions) -> u32 {
+    options.jobs.unwrap_or_else(|| {
+        std::thread::available_parallelism()
+            .map(|n| n.get() as u32)
+            .unwrap_or(1)
+    })
+}
+
+/// Finds where SDL comes from for the targets, starting ndk-build for them
+/// if sdl2-sys doesn't build it. ndk-build runs alongside cargo, so it gets
+/// half of the jobs; the number of jobs left for cargo is returned.
+fn start_sdl(
     manifest_path: &Path,
-    build_target: BuildTarget,
-    build_profile: BuildProfile,
-    ks_file: Option<String>,
-    ks_pass: Option<String>,
-) {
-    for k in &["ANDROID_HOME", "ANDROID_NDK_HOME", "SDL"] {
-        let _check_val = get_env_var(k);
+    targets: &[&str],
+    profile: BuildProfile,
+    jobs: u32,
+) -> (SdlSource, u32) {
+    match find_crate_sdl(manifest_path, targets) {
+        Some(crate_sdl) => {
+            // Only SDL's java sources are needed
+            if crate_sdl.android_project.is_none() {
+                get_sdl_dir(manifest_path);
+            }
+            (SdlSource::Crate(crate_sdl), jobs)
+        }
+        None => {
+            get_sdl_dir(manifest_path);
+            if jobs < 2 {
+                // Not enough jobs to share, SDL is built first
+                let sdl =
+                    SdlSource::NdkBuild(build_sdl_for_android(manifest_path, targets, profile, 1));
+                sdl.wait(targets);
+                return (sdl, jobs);
+            }
+            // SDL is built in the background, the Rust build only waits for
+            // it before linking.
+            let sdl_jobs = jobs / 2;
+            (
+                SdlSource::NdkBuild(build_sdl_for_android(
+                    manifest_path,
+                    targets,
+                    profile,
+                    sdl_jobs,
+                )),
+                jobs - sdl_jobs,
+            )
+        }
     }
+}
+
+/// Builds the APK and returns the unstripped libmain.so for each target.
+fn build_android(manifest_path: &Path, options: &BuildOptions) -> HashMap<String, String> {
+    get_android_sdk_dir(manifest_path);
+    get_android_ndk_dir(manifest_path);
+
+    let targets = ANDROID_TARGETS.to_vec();
 
-    let targets = vec![
-        "aarch64-linux-android",
-        "armv7-linux-androideabi",
-        "i686-linux-android",
-    ];
+    let start = Instant::now();
+    let (sdl, jobs) = start_sdl(manifest_path, &targets, options.profile, get_jobs(options));
 
-    build_sdl_for_android(manifest_path, &targets, build_profile);
-    let target_artifacts = build_bin_as_lib(manifest_path, build_target, &targets, build_profile);
-    build_android_project(
+    let stage = Stage::start("rust");
+    let target_artifacts = build_bin_as_lib(
         manifest_path,
-        &target_artifacts,
-        build_profile,
-        ks_file,
-        ks_pass,
+        &options.build_target,
+        &targets,
+        options.profile,
+        jobs,
+        sdl.clone(),
     );
+    sdl.wait(&targets);
+    verify_sdl_main(manifest_path, &target_artifacts);
+    for (target, artifact_path) in &target_artifacts {
+        artifact(
+            "so",
+            Path::new(artifact_path),
+            Some(get_target_android_name(target)),
+        );
+    }
+    stage.finish();
+
+    build_android_project(manifest_path, &target_artifacts, options, &sdl);
+
+    for apk in copy_output_apks(
+        manifest_path,
+        &options.build_target,
+        options.profile,
+        options.out_dir.as_deref(),
+    ) {
+        status(&format!("Finished: {}", apk.display()));
+    }
+    print_timings(start);
+
+    target_artifacts
 }
 
-fn run_android(
-    manifest_path: &Path,
-    build_target: BuildTarget,
-    build_profile: BuildProfile,
-    ks_file: Option<String>,
-    ks_pass: Option<String>,
-) {
-    build_android(manifest_path, build_target, build_profile, ks_file, ks_pass);
+/// Installs the APK built for the connected device and stops the app if it
+/// is running.
+fn install_android(manifest_path: &Path, options: &BuildOptions) {
+    let build_profile = options.profile;
 
     let manifest_dir = manifest_path.parent().unwrap();
 
-    let appid = get_android_app_id(manifest_path);
-    let output_apk = match build_profile {
-        BuildProfile::Debug => "debug/app-debug.apk",
-        BuildProfile::Release => "release/app-release.apk",
-    };
+    let mut appid = get_android_app_id(manifest_path);
+    if options.test_app {
+        appid.push_str(TEST_APP_ID_SUFFIX);
+    }
+    let output_apk = select_device_apk(
+        manifest_path,
+        &get_output_apks(manifest_dir, build_profile),
+    );
 
-    let p = Path::new(&*get_env_var("ANDROID_HOME")).join("platform-tools/adb");
-    assert!(Command::new(p.clone())
+    let p = get_adb(manifest_path);
+    assert!(adb_command(&p)
         .args([
-            "-d",
             "install",
             "-r",
-            &*manifest_dir
-                .join("target/android-project/app/build/outputs/apk/")
-                .join(output_apk)
-                .into_os_string()
-                .into_string()
-                .unwrap()
+            &*output_apk.into_os_string().into_string().unwrap()
         ])
+        .stdout(subprocess_stdout())
         .status()
         .unwrap()
         .success());
 
-    assert!(Command::new(p.clone())
+    assert!(adb_command(&p)
         .args(["shell", "am", "force-stop", &*appid])
+        .stdout(subprocess_stdout())
         .status()
         .unwrap()
         .success());
+}
+
+/// Starts the installed app with the arguments and environment for it.
+fn start_android(manifest_path: &Path, options: &BuildOptions) {
+    let appid = get_android_app_id(manifest_path);
+    let p = get_adb(manifest_path);
 
     let mut activity = appid.clone();
-    activity.push_str("/.MainActivity");
+    activity.push('/');
+    activity.push_str(&get_launcher_activity(manifest_path));
 
-    assert!(Command::new(p.clone())
+    assert!(adb_command(&p)
         .args(["shell", "am", "start", "-W", "-n", &*activity])
+        .args(string_array_extra(INTENT_ARGS, &options.app_args))
+        .args(string_array_extra(INTENT_ENV, &options.app_env))
+        .stdout(subprocess_stdout())
         .status()
         .unwrap()
         .success());
+}
 
-    let pid_vec = Command::new(p.clone())
-        .arg("shell")
-        .arg("pidof")
-        .arg(&*appid)
-        .output()
-        .unwrap() //except("Can't get pid")
-        .stdout;
-
-    let pid = std::str::from_utf8(&pid_vec).unwrap().trim();
-    let pid: u32 = pid.parse().unwrap();
+fn run_android(manifest_path: &Path, options: &BuildOptions) {
+    build_android(manifest_path, options);
+    install_android(manifest_path, options);
 
-    println!("Launched with PID: {}", pid);
+    // Started before the app, so its first lines aren't missed
+    let logcat = Logcat::spawn(manifest_path, &get_android_app_id(manifest_path));
+    start_android(manifest_path, options);
 
-    assert!(Command::new(p)
-        .args(["logcat", "-v", "color", "--pid", &*pid.to_string()])
-        .status()
-        .unwrap()
-        .success());
+    std::process::exit(logcat.follow(manifest_path, &options.logcat_filter));
 }
 
 fn main() {
@@ -191,6 +401,51 @@ fn main() {
         }
     };
 
+    match args.message_format.as_deref() {
+        None | Some("human") => {}
+        Some("json") => set_message_format_json(),
+        Some(format) => {
+            eprintln!("Error: Unknown message format: {}.", format);
+            println!("{}", HELP);
+            std::process::exit(1);
+        }
+    }
+
+    if let Some(variable) = args.app_env.iter().find(|v| !v.contains('=')) {
+        eprintln!("Error: --env needs KEY=VALUE, got {}.", variable);
+        std::process::exit(1);
+    }
+
+    let logcat_filter = match LogcatFilter::new(&args.tags, args.level.as_deref()) {
+        Ok(filter) => filter,
+        Err(e) => {
+            eprintln!("Error: {}.", e);
+            println!("{}", HELP);
+            std::process::exit(1);
+        }
+    };
+
+    // These commands don't need an existing Cargo.toml
+    match &*args.command {
+        "doctor" => return doctor(Path::new(&args.manifest_path)),
+        "new" => match &args.name {
+            Some(name) => return new_project(name),
+            None => {
+                eprintln!("Error: Need a name for the new project.");
+                println!("{}", HELP);
+                std::process::exit(1);
+            }
+        },
+        "init" => {
+            let manifest_path = Path::new(&args.manifest_path);
+            return init_project(match manifest_path.parent() {
+                Some(dir) if dir != Path::new("") => dir,
+                _ => Path::new("."),
+            });
+        }
+        _ => {}
+    }
+
     let manifest_path = canonicalize(args.manifest_path).unwrap();
 
     let build_target = match &args.example {
@@ -204,26 +459,53 @@ fn main() {
         BuildProfile::Debug
     };
 
-    match &*args.command {
+    let options = BuildOptions {
+        build_target,
+        profile: build_profile,
+        ks_file: args.ks_file.clone(),
+        ks_pass: args.ks_pass.clone(),
+        out_dir: args.out_dir,
+        split_per_abi: args.split_per_abi,
+        universal_apk: args.universal_apk,
+        jobs: args.jobs,
+        logcat_filter,
+        app_args: args.app_args,
+        app_env: args.app_env,
+        debuggable: args.command == "debug",
+        test_app: args.command == "test" && args.in_app,
+    };
+
+    let command = AssertUnwindSafe(|| match &*args.command {
         "sign" => sign_android(&manifest_path, args.ks_file, args.ks_pass),
-        "build" => build_android(
-            &manifest_path,
-            build_target,
-            build_profile,
-            args.ks_file,
-            args.ks_pass,
-        ),
-        "run" => run_android(
+        "build" => {
+            build_android(&manifest_path, &options);
+        }
+        "run" => run_android(&manifest_path, &options),
+        "watch" => watch_android(&manifest_path, &options),
+        "logcat" => logcat(&manifest_path, &options.logcat_filter),
+        "debug" => debug_android(&manifest_path, &options, args.print_commands),
+        "test" if args.in_app => test_in_app(
             &manifest_path,
-            build_target,
-            build_profile,
-            args.ks_file,
-            args.ks_pass,
+            &options,
+            &match &args.test {
+                Some(test) => TestHarness::Test(test),
+                None => TestHarness::Unit {
+                    bin: args.bin.as_deref(),
+                },
+            },
         ),
+        "test" => test_android(&manifest_path, &options),
         _ => {
             eprintln!("Unknown command: {}.", args.command);
             println!("{}", HELP);
             std::process::exit(1);
         }
+    });
+    if let Err(payload) = catch_unwind(command) {
+        // Already reported
+        if payload.is::<Failed>() {
+            std::process::exit(1);
+        }
+        resume_unwind(payload);
     }
 }
@@ -0,0 +1,156 @@
+use serde_json::{json, Value};
+use std::io::Write;
+use std::path::Path;
+use std::process::Stdio;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::Instant;
+
+static JSON: AtomicBool = AtomicBool::new(false);
+static TIMINGS: Mutex<Vec<(String, f64)>> = Mutex::new(Vec::new());
+
+/// Switches to `--message-format json`. Stdout then only carries one JSON
+/// object per line, all other output goes to stderr.
+pub fn set_message_format_json() {
+    JSON.store(true, Ordering::Relaxed);
+
+    let default_hook = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        let message = match info.payload().downcast_ref::<&str>() {
+            Some(s) => s.to_string(),
+            None => match info.payload().downcast_ref::<String>() {
+                Some(s) => s.clone(),
+                None => "unknown error".to_string(),
+            },
+        };
+        emit(json!({ "reason": "error", "message": message }));
+        default_hook(info);
+    }));
+}
+
+pub fn is_json() -> bool {
+    JSON.load(Ordering::Relaxed)
+}
+
+fn emit(message: Value) {
+    let mut stdout = std::io::stdout().lock();
+    writeln!(stdout, "{message}").unwrap();
+    stdout.flush().unwrap();
+}
+
+/// Where subprocesses like gradle should write their output, so it doesn't
+/// end up between the JSON messages.
+pub fn subprocess_stdout() -> Stdio {
+    if is_json() {
+        Stdio::from(std::io::stderr())
+    } else {
+        Stdio::inherit()
+    }
+}
+
+/// Progress information for humans.
+pub fn status(message: &str) {
+    if is_json() {
+        eprintln!("{message}");
+    } else {
+        println!("{message}");
+    }
+}
+
+pub fn warning(message: &str) {
+    if is_json() {
+        emit(json!({ "reason": "warning", "message": message }));
+    } else {
+        eprintln!("Warning: {message}");
+    }
+}
+
+/// Reports an error. The caller decides whether to exit.
+pub fn error(message: &str) {
+    if is_json() {
+        emit(json!({ "reason": "error", "message": message }));
+    } else {
+        eprintln!("Error: {message}");
+    }
+}
+
+/// What `fail` unwinds with.
+pub struct Failed;
+
+/// Stops the command after an error was reported. Unwinds instead of
+/// exiting, so watch can wait for a fix. main exits with status 1.
+pub fn fail() -> ! {
+    std::panic::resume_unwind(Box::new(Failed))
+}
+
+/// A produced file. `kind` is one of "so", "test", "unsigned-apk",
+/// "signed-apk", "apk" or "aab".
+pub fn artifact(kind: &str, path: &Path, abi: Option<&str>) {
+    if is_json() {
+        emit(json!({
+            "reason": "artifact",
+            "kind": kind,
+            "abi": abi,
+            "path": path,
+        }));
+    }
+}
+
+/// The result of a test run in the app: "ok", "failed" or "ignored".
+pub fn test_result(name: &str, result: &str) {
+    if is_json() {
+        emit(json!({ "reason": "test", "name": name, "result": result }));
+    }
+}
+
+pub struct Stage {
+    name: String,
+    start: Instant,
+}
+
+impl Stage {
+    pub fn start(name: &str) -> Self {
+        if is_json() {
+            emit(json!({ "reason": "stage-started", "stage": name }));
+        }
+        Self {
+            name: name.to_string(),
+            start: Instant::now(),
+        }
+    }
+
+    pub fn finish(self) {
+        let duration = self.start.elapsed().as_secs_f64();
+        if is_json() {
+            emit(json!({
+                "reason": "stage-finished",
+                "stage": self.name,
+                "duration": duration,
+            }));
+        }
+        TIMINGS.lock().unwrap().push((self.name, duration));
+    }
+}
+
+/// Prints how long each stage finished since the last call took. Stages may
+/// have run in parallel, so they can add up to more than the total.
+pub fn print_timings(start: Instant) {
+    let mut timings = TIMINGS.lock().unwrap();
+    let width = timings
+        .iter()
+        .map(|(name, _)| name.len())
+        .max()
+        .unwrap_or(0)
+        .max("total".len());
+
+    status("Timings:");
+    for (name, duration) in timings.iter() {
+        status(&format!("  {name:width$}  {duration:7.1}s"));
+    }
+    status(&format!(
+        "  {:width$}  {:7.1}s",
+        "total",
+        start.elapsed().as_secs_f64()
+    ));
+    timings.clear();
+}
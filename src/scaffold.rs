@@ -0,0 +1,208 @@
+use image::{Rgba, RgbaImage};
+use std::fs::{create_dir_all, read_to_string, write, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+use toml::Table;
+
+/// Since edition 2024 no_mangle has to be marked unsafe.
+pub fn entry_point_source(edition: &str) -> String {
+    let no_mangle = if edition >= "2024" {
+        "#[unsafe(no_mangle)]"
+    } else {
+        "#[no_mangle]"
+    };
+
+    format!(
+        r#"
+// The entry point on Android must be called SDL_main, and must be
+// attributed {no_mangle}. It calls the regular main function, so the
+// same program runs both on desktop and on Android.
+{no_mangle}
+#[allow(non_snake_case)]
+pub fn SDL_main() {{
+    main();
+}}
+"#
+    )
+}
+
+fn main_source(title: &str, edition: &str) -> String {
+    format!(
+        r#"use sdl2::event::Event;
+use sdl2::pixels::Color;
+use std::time::Duration;
+{}
+pub fn main() {{
+    let sdl = sdl2::init().unwrap();
+    let video_subsystem = sdl.video().unwrap();
+
+    let window = video_subsystem
+        .window("{title}", 800, 600)
+        .resizable()
+        .build()
+        .unwrap();
+    let mut canvas = window.into_canvas().build().unwrap();
+
+    let mut event_pump = sdl.event_pump().unwrap();
+    loop {{
+        for event in event_pump.poll_iter() {{
+            if let Event::Quit {{ .. }} = event {{
+                return;
+            }}
+        }}
+
+        canvas.set_draw_color(Color::RGB(0, 128, 192));
+        canvas.clear();
+        canvas.present();
+        std::thread::sleep(Duration::from_millis(16));
+    }}
+}}
+"#,
+        entry_point_source(edition)
+    )
+}
+
+fn metadata_section(name: &str, title: &str) -> String {
+    format!(
+        r#"[package.metadata.android]
+title = "{title}"
+package_name = "com.example.{}"
+icon = "icon.png"
+assets = "assets"
+"#,
+        name.replace('-', "_")
+    )
+}
+
+/// "my-game" becomes "My Game".
+fn title_from_name(name: &str) -> String {
+    name.split(['-', '_'])
+        .filter(|word| !word.is_empty())
+        .map(|word| {
+            let mut chars = word.chars();
+            let first = chars.next().unwrap().to_uppercase();
+            first.chain(chars).collect::<String>()
+        })
+        .collect::<Vec<String>>()
+        .join(" ")
+}
+
+fn write_placeholder_icon(path: &Path) {
+    let size = 512;
+    let radius = size as f32 * 0.3;
+    let icon = RgbaImage::from_fn(size, size, |x, y| {
+        let dx = x as f32 + 0.5 - size as f32 / 2.0;
+        let dy = y as f32 + 0.5 - size as f32 / 2.0;
+        if (dx * dx + dy * dy).sqrt() < radius {
+            Rgba([255, 255, 255, 255])
+        } else {
+            Rgba([0, 128, 192, 255])
+        }
+    });
+    icon.save(path).unwrap();
+}
+
+fn create_project_files(dir: &Path, name: &str) {
+    let title = title_from_name(name);
+
+    create_dir_all(dir.join("src")).unwrap();
+    create_dir_all(dir.join("assets")).unwrap();
+    write(
+        dir.join("Cargo.toml"),
+        format!(
+            r#"[package]
+name = "{name}"
+version = "0.1.0"
+edition = "2021"
+
+{}
+[dependencies]
+sdl2 = "0.35.2"
+"#,
+            metadata_section(name, &title)
+        ),
+    )
+    .unwrap();
+    write(dir.join("src/main.rs"), main_source(&title, "2021")).unwrap();
+    write(dir.join(".gitignore"), "/target\n").unwrap();
+    write_placeholder_icon(&dir.join("icon.png"));
+}
+
+/// Creates a new SDL Android project in the directory `name`.
+pub fn new_project(name: &str) {
+    let dir = Path::new(name);
+    if dir.exists() {
+        eprintln!("Error: {} already exists.", dir.display());
+        std::process::exit(1);
+    }
+
+    let name = dir.file_name().unwrap().to_str().unwrap();
+    create_project_files(dir, name);
+    println!("Created {}", dir.display());
+}
+
+/// Sets up the crate in `dir` for Android. Creates a new project if there
+/// is no Cargo.toml, otherwise adds what's missing.
+pub fn init_project(dir: &Path) {
+    let manifest_path = dir.join("Cargo.toml");
+    if !manifest_path.exists() {
+        let dir = dir.canonicalize().unwrap();
+        let name = dir.file_name().unwrap().to_str().unwrap();
+        create_project_files(&dir, name);
+        println!("Created project in {}", dir.display());
+        return;
+    }
+
+    let manifest = read_to_string(&manifest_path)
+        .unwrap()
+        .parse::<Table>()
+        .unwrap();
+    let package = manifest["package"].as_table().unwrap();
+    let name = package["name"].as_str().unwrap();
+    let edition = package
+        .get("edition")
+        .and_then(|e| e.as_str())
+        .unwrap_or("2015");
+
+    let android = package
+        .get("metadata")
+        .and_then(|m| m.get("android"))
+        .and_then(|a| a.as_table());
+    if android.is_none() {
+        let mut file = OpenOptions::new()
+            .append(true)
+            .open(&manifest_path)
+            .unwrap();
+        write!(file, "\n{}", metadata_section(name, &title_from_name(name))).unwrap();
+        println!("Added [package.metadata.android] to {}", manifest_path.display());
+
+        if !dir.join("icon.png").exists() {
+            write_placeholder_icon(&dir.join("icon.png"));
+            println!("Created placeholder icon.png");
+        }
+        create_dir_all(dir.join("assets")).unwrap();
+    }
+
+    let main_path = dir.join("src/main.rs");
+    match read_to_string(&main_path) {
+        Ok(source) if source.contains("SDL_main") => {}
+        Ok(_) => {
+            let mut file = OpenOptions::new().append(true).open(&main_path).unwrap();
+            write!(file, "{}", entry_point_source(edition)).unwrap();
+            println!("Added SDL_main to {}", main_path.display());
+        }
+        Err(_) => {
+            create_dir_all(dir.join("src")).unwrap();
+            write(&main_path, main_source(&title_from_name(name), edition)).unwrap();
+            println!("Created {}", main_path.display());
+        }
+    }
+
+    let has_sdl2 = match manifest.get("dependencies").and_then(|d| d.as_table()) {
+        Some(dependencies) => dependencies.contains_key("sdl2"),
+        None => false,
+    };
+    if !has_sdl2 {
+        println!("Note: add sdl2 to your [dependencies], e.g. sdl2 = \"0.35.2\"");
+    }
+}
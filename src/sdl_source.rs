@@ -0,0 +1,281 @@
+use crate::message::*;
+use crate::util::*;
+use sha2::{Digest, Sha256};
+use std::env;
+use std::fs::{create_dir_all, read, read_to_string, remove_dir_all, rename, write};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// Where downloaded SDL sources are kept, CARGO_SDL_APK_CACHE or
+/// ~/.cache/cargo-sdl-apk.
+pub fn get_sdl_cache_dir() -> PathBuf {
+    if let Some(dir) = env::var_os("CARGO_SDL_APK_CACHE").filter(|d| !d.is_empty()) {
+        return PathBuf::from(dir);
+    }
+    match env::var_os("XDG_CACHE_HOME").filter(|d| !d.is_empty()) {
+        Some(dir) => PathBuf::from(dir).join("cargo-sdl-apk"),
+        None => PathBuf::from(env::var_os("HOME").expect("HOME is not set"))
+            .join(".cache/cargo-sdl-apk"),
+    }
+}
+
+fn get_metadata(manifest_path: &Path, key: &str) -> Option<String> {
+    if !manifest_path.exists() {
+        return None;
+    }
+    get_toml_string(manifest_path, vec!["package", "metadata", "android", key])
+}
+
+fn git_cache_dir(cache_dir: &Path, url: &str, version: Option<&str>) -> PathBuf {
+    let name: String = url
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect();
+    cache_dir
+        .join("git")
+        .join(format!("{name}-{}", version.unwrap_or("HEAD")))
+}
+
+fn version_cache_dir(cache_dir: &Path, version: &str) -> PathBuf {
+    cache_dir.join(format!("SDL2-{version}"))
+}
+
+/// Holds the sha256 of the tarball the cached release was unpacked from.
+fn version_sha256_file(cache_dir: &Path, version: &str) -> PathBuf {
+    cache_dir.join(format!("SDL2-{version}.sha256"))
+}
+
+/// Finds the SDL source without fetching anything. From `sdl_path`, the
+/// cached `sdl_git` checkout or `sdl_version` release, or $SDL.
+pub fn find_sdl_dir(manifest_path: &Path) -> Option<PathBuf> {
+    find_sdl_dir_in_cache(manifest_path, &get_sdl_cache_dir())
+}
+
+fn find_sdl_dir_in_cache(manifest_path: &Path, cache_dir: &Path) -> Option<PathBuf> {
+    let version = get_metadata(manifest_path, "sdl_version");
+
+    if let Some(path) = get_metadata(manifest_path, "sdl_path") {
+        return Some(manifest_path.parent().unwrap().join(path));
+    }
+    if let Some(url) = get_metadata(manifest_path, "sdl_git") {
+        return Some(git_cache_dir(cache_dir, &url, version.as_deref()))
+            .filter(|dir| dir.is_dir());
+    }
+    if let Some(version) = version {
+        // Not unpacked from the pinned tarball, it has to be fetched again
+        if let Some(expected) = get_metadata(manifest_path, "sdl_sha256") {
+            let cached = read_to_string(version_sha256_file(cache_dir, &version)).unwrap_or_default();
+            if cached.trim() != expected.to_lowercase() {
+                return None;
+            }
+        }
+        return Some(version_cache_dir(cache_dir, &version)).filter(|dir| dir.is_dir());
+    }
+
+    env::var("SDL")
+        .ok()
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+}
+
+fn sha256_file(path: &Path) -> String {
+    let data = read(path).unwrap_or_else(|e| panic!("Unable to read {}: {e}", path.display()));
+    format!("{:x}", Sha256::digest(data))
+}
+
+fn clone_sdl(url: &str, version: Option<&str>, dest: &Path) {
+    let tmp = dest.with_extension("tmp");
+    if tmp.exists() {
+        remove_dir_all(&tmp).unwrap();
+    }
+    create_dir_all(dest.parent().unwrap()).unwrap();
+
+    status(&format!("Cloning SDL from {url}..."));
+    let mut cmd = Command::new("git");
+    cmd.args(["clone", "--depth", "1"]);
+    if let Some(version) = version {
+        cmd.args(["--branch", &format!("release-{version}")]);
+    }
+    assert!(
+        cmd.arg(url)
+            .arg(&tmp)
+            .stdout(subprocess_stdout())
+            .status()
+            .expect("Unable to run git")
+            .success(),
+        "Unable to clone SDL from {url}"
+    );
+
+    rename(tmp, dest).unwrap();
+}
+
+/// Downloads the release tarball, unless `sdl_tarball` points to a local
+/// one, verifies it and unpacks it into the cache.
+fn fetch_sdl_release(manifest_path: &Path, cache_dir: &Path, version: &str, dest: &Path) {
+    create_dir_all(cache_dir).unwrap();
+
+    let tarball = match get_metadata(manifest_path, "sdl_tarball") {
+        Some(tarball) => manifest_path.parent().unwrap().join(tarball),
+        None => {
+            let mirror = get_metadata(manifest_path, "sdl_mirror").unwrap_or(format!(
+                "https://github.com/libsdl-org/SDL/releases/download/release-{version}"
+            ));
+            let url = format!("{}/SDL2-{version}.tar.gz", mirror.trim_end_matches('/'));
+            let tarball = cache_dir.join(format!("SDL2-{version}.tar.gz"));
+
+            status(&format!("Downloading {url}..."));
+            assert!(
+                Command::new("curl")
+                    .args(["-fL", "-o"])
+                    .arg(&tarball)
+                    .arg(&url)
+                    .stdout(subprocess_stdout())
+                    .status()
+                    .expect("Unable to run curl")
+                    .success(),
+                "Unable to download {url}"
+            );
+            tarball
+        }
+    };
+
+    let sha256 = sha256_file(&tarball);
+    match get_metadata(manifest_path, "sdl_sha256") {
+        Some(expected) if expected.to_lowercase() == sha256 => {}
+        Some(expected) => {
+            error(&format!(
+                "Checksum mismatch for {}: expected {expected}, got {sha256}.",
+                tarball.display()
+            ));
+            fail();
+        }
+        None => warning(&format!(
+            "{} is not verified, set sdl_sha256 = \"{sha256}\" to pin it",
+            tarball.display()
+        )),
+    }
+
+    let tmp = dest.with_extension("tmp");
+    if tmp.exists() {
+        remove_dir_all(&tmp).unwrap();
+    }
+    create_dir_all(&tmp).unwrap();
+    assert!(
+        Command::new("tar")
+            .args(["-xzf"])
+            .arg(&tarball)
+            .args(["--strip-components", "1", "-C"])
+            .arg(&tmp)
+            .status()
+            .expect("Unable to run tar")
+            .success(),
+        "Unable to unpack {}",
+        tarball.display()
+    );
+
+    if dest.exists() {
+        remove_dir_all(dest).unwrap();
+    }
+    rename(tmp, dest).unwrap();
+    write(version_sha256_file(cache_dir, version), sha256).unwrap();
+}
+
+/// Finds the SDL source, fetching it into the cache if needed.
+pub fn get_sdl_dir(manifest_path: &Path) -> PathBuf {
+    get_sdl_dir_in_cache(manifest_path, &get_sdl_cache_dir())
+}
+
+fn get_sdl_dir_in_cache(manifest_path: &Path, cache_dir: &Path) -> PathBuf {
+    if let Some(dir) = find_sdl_dir_in_cache(manifest_path, cache_dir) {
+        return dir;
+    }
+
+    let version = get_metadata(manifest_path, "sdl_version");
+    if let Some(url) = get_metadata(manifest_path, "sdl_git") {
+        let dest = git_cache_dir(cache_dir, &url, version.as_deref());
+        clone_sdl(&url, version.as_deref(), &dest);
+        return dest;
+    }
+    if let Some(version) = version {
+        let dest = version_cache_dir(cache_dir, &version);
+        fetch_sdl_release(manifest_path, cache_dir, &version, &dest);
+        return dest;
+    }
+
+    panic!("Unable to find SDL. Set sdl_version in [package.metadata.android] or the SDL env var, or run `cargo sdl-apk doctor` to check your setup.");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::panic::catch_unwind;
+
+    const VERSION: &str = "2.0.99";
+
+    /// A project using a small generated SDL tarball. Returns the checksum
+    /// of the tarball.
+    fn make_project(dir: &Path) -> String {
+        let source = dir.join(format!("SDL2-{VERSION}"));
+        create_dir_all(source.join("include")).unwrap();
+        write(source.join("include/SDL.h"), "/* SDL */\n").unwrap();
+
+        let tarball = dir.join(format!("SDL2-{VERSION}.tar.gz"));
+        assert!(Command::new("tar")
+            .arg("-czf")
+            .arg(&tarball)
+            .arg("-C")
+            .arg(dir)
+            .arg(format!("SDL2-{VERSION}"))
+            .status()
+            .unwrap()
+            .success());
+        remove_dir_all(&source).unwrap();
+
+        sha256_file(&tarball)
+    }
+
+    fn write_manifest(dir: &Path, sha256: &str) -> PathBuf {
+        let manifest_path = dir.join("Cargo.toml");
+        write(
+            &manifest_path,
+            format!(
+                "[package]\nname = \"app\"\nversion = \"0.1.0\"\n\n\
+                 [package.metadata.android]\nsdl_version = \"{VERSION}\"\n\
+                 sdl_tarball = \"SDL2-{VERSION}.tar.gz\"\nsdl_sha256 = \"{sha256}\"\n"
+            ),
+        )
+        .unwrap();
+        manifest_path
+    }
+
+    #[test]
+    fn release_is_cached_and_verified() {
+        let dir = TempDir::new("cached");
+        let dir = dir.path();
+        let sha256 = make_project(dir);
+        let cache = dir.join("cache");
+        let manifest_path = write_manifest(dir, &sha256);
+
+        assert_eq!(find_sdl_dir_in_cache(&manifest_path, &cache), None);
+        let sdl_dir = get_sdl_dir_in_cache(&manifest_path, &cache);
+        assert_eq!(sdl_dir, cache.join(format!("SDL2-{VERSION}")));
+        assert!(sdl_dir.join("include/SDL.h").is_file());
+        let cached_sha256 = cache.join(format!("SDL2-{VERSION}.sha256"));
+        assert_eq!(read_to_string(&cached_sha256).unwrap(), sha256);
+
+        // Found offline, without the tarball
+        let tarball = dir.join(format!("SDL2-{VERSION}.tar.gz"));
+        let moved = dir.join("moved.tar.gz");
+        rename(&tarball, &moved).unwrap();
+        assert_eq!(get_sdl_dir_in_cache(&manifest_path, &cache), sdl_dir);
+        rename(&moved, &tarball).unwrap();
+
+        // Pinning another tarball doesn't trust the cached one, and fails
+        // when the tarball doesn't match
+        write_manifest(dir, &"0".repeat(64));
+        assert_eq!(find_sdl_dir_in_cache(&manifest_path, &cache), None);
+        let failed = catch_unwind(|| get_sdl_dir_in_cache(&manifest_path, &cache)).unwrap_err();
+        assert!(failed.is::<Failed>());
+        assert_eq!(read_to_string(&cached_sha256).unwrap(), sha256);
+    }
+}
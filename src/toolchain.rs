@@ -0,0 +1,129 @@
+use crate::util::*;
+use std::env;
+use std::fs::{read_dir, read_to_string};
+use std::path::{Path, PathBuf};
+
+/// Reads a key from a gradle style local.properties file.
+fn get_local_property(file_name: &Path, key: &str) -> Option<String> {
+    read_to_string(file_name)
+        .ok()?
+        .lines()
+        .filter_map(|line| line.split_once('='))
+        .find(|(k, _)| k.trim() == key)
+        .map(|(_, v)| v.trim().replace("\\:", ":").replace("\\\\", "\\"))
+}
+
+fn env_dir(key: &str) -> Option<PathBuf> {
+    env::var(key)
+        .ok()
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+}
+
+/// The `ndk_version` set in the metadata, if any.
+fn get_ndk_version(manifest_path: &Path) -> Option<String> {
+    if !manifest_path.exists() {
+        return None;
+    }
+    get_toml_string(
+        manifest_path,
+        vec!["package", "metadata", "android", "ndk_version"],
+    )
+}
+
+/// The versions of the NDKs installed in the SDK, oldest first.
+fn get_installed_ndk_versions(sdk: &Path) -> Vec<String> {
+    let mut versions: Vec<String> = read_dir(sdk.join("ndk"))
+        .map(|dirs| {
+            dirs.filter_map(|d| d.ok())
+                .filter(|d| d.path().is_dir())
+                .map(|d| d.file_name().to_string_lossy().into_owned())
+                .collect()
+        })
+        .unwrap_or_default();
+    versions.sort_by_key(|v| parse_version(v));
+    versions
+}
+
+fn parse_version(version: &str) -> Vec<u64> {
+    version
+        .split(|c: char| !c.is_ascii_digit())
+        .filter_map(|part| part.parse().ok())
+        .collect()
+}
+
+/// Finds the Android SDK in ANDROID_HOME, ANDROID_SDK_ROOT, the project's
+/// local.properties or the default Android Studio locations.
+pub fn find_android_sdk_dir(manifest_path: &Path) -> Option<PathBuf> {
+    let manifest_dir = manifest_path.parent().unwrap();
+    let home = env_dir("HOME");
+
+    env_dir("ANDROID_HOME")
+        .or_else(|| env_dir("ANDROID_SDK_ROOT"))
+        .or_else(|| {
+            get_local_property(&manifest_dir.join("local.properties"), "sdk.dir")
+                .map(PathBuf::from)
+        })
+        .or_else(|| {
+            let home = home?;
+            [home.join("Android/Sdk"), home.join("Library/Android/sdk")]
+                .into_iter()
+                .find(|dir| dir.is_dir())
+        })
+}
+
+pub fn get_android_sdk_dir(manifest_path: &Path) -> PathBuf {
+    find_android_sdk_dir(manifest_path).unwrap_or_else(|| {
+        panic!("Unable to find the Android SDK. Set ANDROID_HOME or run `cargo sdl-apk doctor` to check your setup.")
+    })
+}
+
+/// Finds the Android NDK. If `ndk_version` is set in the metadata,
+/// `$SDK/ndk/<ndk_version>` is used. Otherwise ANDROID_NDK_HOME,
+/// ANDROID_NDK_ROOT, the project's local.properties and the newest NDK
+/// installed in the SDK are tried.
+pub fn find_android_ndk_dir(manifest_path: &Path) -> Option<PathBuf> {
+    let manifest_dir = manifest_path.parent().unwrap();
+    let sdk = find_android_sdk_dir(manifest_path);
+
+    if let Some(version) = get_ndk_version(manifest_path) {
+        let dir = sdk?.join("ndk").join(version);
+        return if dir.is_dir() { Some(dir) } else { None };
+    }
+
+    env_dir("ANDROID_NDK_HOME")
+        .or_else(|| env_dir("ANDROID_NDK_ROOT"))
+        .or_else(|| {
+            get_local_property(&manifest_dir.join("local.properties"), "ndk.dir")
+                .map(PathBuf::from)
+        })
+        .or_else(|| {
+            let sdk = sdk?;
+            let newest = get_installed_ndk_versions(&sdk)
+                .last()
+                .map(|version| sdk.join("ndk").join(version));
+            newest.or_else(|| Some(sdk.join("ndk-bundle")).filter(|dir| dir.is_dir()))
+        })
+}
+
+pub fn get_android_ndk_dir(manifest_path: &Path) -> PathBuf {
+    if let Some(dir) = find_android_ndk_dir(manifest_path) {
+        return dir;
+    }
+
+    // ANDROID_NDK_HOME doesn't matter once ndk_version is set
+    let Some(version) = get_ndk_version(manifest_path) else {
+        panic!("Unable to find the Android NDK. Set ANDROID_NDK_HOME or ndk_version, or run `cargo sdl-apk doctor` to check your setup.")
+    };
+    let sdk = get_android_sdk_dir(manifest_path);
+    let installed = get_installed_ndk_versions(&sdk);
+    let installed = if installed.is_empty() {
+        "No NDK is installed there".to_string()
+    } else {
+        format!("Installed versions: {}", installed.join(", "))
+    };
+    panic!(
+        "Unable to find NDK {version} at {}. {installed}. Install it with `sdkmanager --install \"ndk;{version}\"` or change ndk_version.",
+        sdk.join("ndk").join(&version).display()
+    )
+}
@@ -1,19 +1,10 @@
-use std::env;
 use std::fs::read_to_string;
 use std::path::Path;
+#[cfg(test)]
+use std::path::PathBuf;
 use toml::value::Value;
 use toml::Table;
 
-pub fn get_env_var(key: &str) -> String {
-    for (k, v) in env::vars() {
-        if k == key {
-            return v;
-        }
-    }
-
-    panic!("Need env var: {}", key);
-}
-
 fn get_toml_string_rec(table: &Table, mut path: Vec<&str>) -> Option<String> {
     if path.len() == 1 {
         if !table.contains_key(path[0]) {
@@ -89,3 +80,31 @@ pub fn get_toml_string_array(file_name: &Path, path: Vec<&str>) -> Option<Vec<St
 
     get_toml_string_array_rec(&config, path)
 }
+
+/// A temporary dir for a test, removed when dropped, also when the test
+/// fails.
+#[cfg(test)]
+pub struct TempDir(PathBuf);
+
+#[cfg(test)]
+impl TempDir {
+    pub fn new(name: &str) -> Self {
+        let dir = std::env::temp_dir().join(format!("cargo-sdl-apk-{name}-{}", std::process::id()));
+        if dir.exists() {
+            std::fs::remove_dir_all(&dir).unwrap();
+        }
+        std::fs::create_dir_all(&dir).unwrap();
+        TempDir(dir)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+}
+
+#[cfg(test)]
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        std::fs::remove_dir_all(&self.0).ok();
+    }
+}
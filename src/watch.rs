@@ -0,0 +1,208 @@
+use crate::android_project::*;
+use crate::assets::*;
+use crate::build_bin_as_lib::*;
+use crate::entry_point::*;
+use crate::logcat::*;
+use crate::message::*;
+use crate::{
+    build_android, get_jobs, install_android, start_android, BuildOptions, ANDROID_TARGETS,
+};
+use std::collections::HashMap;
+use std::fs::{metadata, read_dir};
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime};
+
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+/// Editors often write a file more than once when saving.
+const SETTLE_TIME: Duration = Duration::from_millis(200);
+
+/// What has to be rebuilt for the changed files.
+#[derive(Default)]
+struct Changes {
+    rust: bool,
+    assets: bool,
+    project: bool,
+}
+
+impl Changes {
+    /// Also rebuilds what `other` needs, after a rebuild failed.
+    fn add(&mut self, other: Changes) {
+        self.rust |= other.rust;
+        self.assets |= other.assets;
+        self.project |= other.project;
+    }
+
+    fn any(&self) -> bool {
+        self.rust || self.assets || self.project
+    }
+
+    fn describe(&self) -> String {
+        let mut changed = vec![];
+        if self.project {
+            changed.push("Cargo.toml");
+        }
+        if self.rust {
+            changed.push("sources");
+        }
+        if self.assets {
+            changed.push("assets");
+        }
+        format!("Changed {}", changed.join(", "))
+    }
+}
+
+/// The watched files, with when they were last modified.
+struct Snapshot {
+    rust: HashMap<PathBuf, SystemTime>,
+    assets: HashMap<PathBuf, SystemTime>,
+    manifest: Option<SystemTime>,
+}
+
+fn collect_mtimes(path: &Path, mtimes: &mut HashMap<PathBuf, SystemTime>) {
+    let Ok(meta) = metadata(path) else { return };
+    if meta.is_dir() {
+        let Ok(entries) = read_dir(path) else { return };
+        for entry in entries.flatten() {
+            collect_mtimes(&entry.path(), mtimes);
+        }
+    } else if let Ok(modified) = meta.modified() {
+        mtimes.insert(path.to_path_buf(), modified);
+    }
+}
+
+impl Snapshot {
+    fn take(manifest_path: &Path, build_target: &BuildTarget) -> Self {
+        let manifest_dir = manifest_path.parent().unwrap();
+
+        let mut rust = HashMap::new();
+        collect_mtimes(&manifest_dir.join("src"), &mut rust);
+        collect_mtimes(&manifest_dir.join("build.rs"), &mut rust);
+        if let BuildTarget::Example(_) = build_target {
+            collect_mtimes(&manifest_dir.join("examples"), &mut rust);
+        }
+
+        let mut assets = HashMap::new();
+        if let Some(dir) = get_assets_dir(manifest_path) {
+            collect_mtimes(&dir, &mut assets);
+        }
+
+        Self {
+            rust,
+            assets,
+            manifest: metadata(manifest_path).and_then(|m| m.modified()).ok(),
+        }
+    }
+
+    fn changes(&self, newer: &Snapshot) -> Changes {
+        Changes {
+            rust: self.rust != newer.rust,
+            assets: self.assets != newer.assets,
+            project: self.manifest != newer.manifest,
+        }
+    }
+}
+
+/// Rebuilds libmain.so into the existing android project. SDL was built by
+/// the first build.
+fn rebuild_rust(manifest_path: &Path, options: &BuildOptions) {
+    let targets = ANDROID_TARGETS.to_vec();
+    let sdl = match find_crate_sdl(manifest_path, &targets) {
+        Some(crate_sdl) => SdlSource::Crate(crate_sdl),
+        None => SdlSource::NdkBuild(SdlBuild::built(&targets)),
+    };
+
+    let stage = Stage::start("rust");
+    let target_artifacts = build_bin_as_lib(
+        manifest_path,
+        &options.build_target,
+        &targets,
+        options.profile,
+        get_jobs(options),
+        sdl,
+    );
+    verify_sdl_main(manifest_path, &target_artifacts);
+    stage.finish();
+
+    copy_android_libs(manifest_path, &target_artifacts);
+}
+
+fn rebuild(manifest_path: &Path, options: &BuildOptions, changes: &Changes) {
+    // Cargo.toml has the metadata the android project is generated from
+    if changes.project {
+        build_android(manifest_path, options);
+        return;
+    }
+
+    let start = Instant::now();
+    if changes.rust {
+        rebuild_rust(manifest_path, options);
+    }
+    if changes.assets {
+        let stage = Stage::start("project");
+        copy_android_assets(manifest_path);
+        stage.finish();
+    }
+    assemble_android_project(manifest_path, options);
+
+    for apk in copy_output_apks(
+        manifest_path,
+        &options.build_target,
+        options.profile,
+        options.out_dir.as_deref(),
+    ) {
+        status(&format!("Finished: {}", apk.display()));
+    }
+    print_timings(start);
+}
+
+/// The watch command. Runs the app like run, then rebuilds, reinstalls and
+/// restarts it when its sources, assets or Cargo.toml change. The log is
+/// shown all along.
+pub fn watch_android(manifest_path: &Path, options: &BuildOptions) {
+    let mut snapshot = Snapshot::take(manifest_path, &options.build_target);
+    let mut pending = Changes::default();
+    build_android(manifest_path, options);
+    install_android(manifest_path, options);
+
+    let logcat = Logcat::spawn(manifest_path, &get_android_app_id(manifest_path));
+    start_android(manifest_path, options);
+
+    std::thread::scope(|scope| {
+        scope.spawn(|| {
+            logcat.watch(
+                manifest_path,
+                &options.logcat_filter,
+                Until::Forever,
+                &mut |_, _| false,
+            );
+        });
+
+        loop {
+            std::thread::sleep(POLL_INTERVAL);
+            if !snapshot
+                .changes(&Snapshot::take(manifest_path, &options.build_target))
+                .any()
+            {
+                continue;
+            }
+
+            std::thread::sleep(SETTLE_TIME);
+            // Taken before building, so changes made meanwhile are seen next
+            let newer = Snapshot::take(manifest_path, &options.build_target);
+            pending.add(snapshot.changes(&newer));
+            snapshot = newer;
+
+            status(&format!("--- {}, rebuilding", pending.describe()));
+            let rebuilt = catch_unwind(AssertUnwindSafe(|| {
+                rebuild(manifest_path, options, &pending);
+                install_android(manifest_path, options);
+                start_android(manifest_path, options);
+            }));
+            match rebuilt {
+                Ok(()) => pending = Changes::default(),
+                Err(_) => error("Rebuilding failed, waiting for changes."),
+            }
+        }
+    });
+}